@@ -0,0 +1,51 @@
+use near_sdk::env;
+
+/// Service Staking errors with stable codes.
+/// Each error panics with the `E<code>: <message>` string, e.g. `E002: Service not staked`
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum StakingError {
+    Unauthorized = 1,
+    ServiceNotStaked = 2,
+    WrongStakingParams = 3,
+    CheckpointNotDue = 4,
+    ZeroReward = 5,
+    MinStakingDurationNotReached = 6,
+    WrongRewardToken = 7,
+    ZeroDeposit = 8,
+    WrongServiceRegistry = 9,
+    WrongServiceId = 10,
+    MaxNumServicesReached = 11
+}
+
+impl StakingError {
+    pub fn code(&self) -> u32 {
+        *self as u32
+    }
+
+    pub fn message(&self) -> &'static str {
+        match self {
+            StakingError::Unauthorized => "Unauthorized",
+            StakingError::ServiceNotStaked => "Service not staked",
+            StakingError::WrongStakingParams => "Wrong staking parameters",
+            StakingError::CheckpointNotDue => "Checkpoint is not due",
+            StakingError::ZeroReward => "Zero reward",
+            StakingError::MinStakingDurationNotReached => "Minimum staking duration is not reached",
+            StakingError::WrongRewardToken => "Wrong reward token",
+            StakingError::ZeroDeposit => "Zero deposit",
+            StakingError::WrongServiceRegistry => "Wrong service registry",
+            StakingError::WrongServiceId => "Wrong service Id",
+            StakingError::MaxNumServicesReached => "Maximum number of services is reached"
+        }
+    }
+
+    pub fn panic(self) -> ! {
+        env::panic_str(&format!("E{:03}: {}", self.code(), self.message()))
+    }
+}
+
+/// Panics with the provided error if the condition does not hold
+pub fn ensure(condition: bool, error: StakingError) {
+    if !condition {
+        error.panic();
+    }
+}
@@ -0,0 +1,46 @@
+use near_sdk::json_types::U128;
+use near_sdk::{near, AccountId};
+
+/// Service Staking events following the NEP-297 standard.
+/// Each event is logged as `EVENT_JSON:{"standard":"olas_service_staking","version":...,"event":...,"data":...}`
+#[near(event_json(standard = "olas_service_staking"))]
+pub enum StakingEvent {
+    #[event_version("1.0.0")]
+    ServiceStaked {
+        service_id: u32,
+        owner_id: AccountId,
+        multisig: AccountId,
+        nonce: u32
+    },
+
+    #[event_version("1.0.0")]
+    Checkpoint {
+        epoch: u64,
+        available_rewards: U128,
+        service_ids: Vec<u32>,
+        rewards: Vec<U128>
+    },
+
+    #[event_version("1.0.0")]
+    RewardClaimed {
+        service_id: u32,
+        owner_id: AccountId,
+        multisig: AccountId,
+        amount: U128
+    },
+
+    #[event_version("1.0.0")]
+    ServiceUnstaked {
+        service_id: u32,
+        owner_id: AccountId,
+        multisig: AccountId,
+        reward: U128
+    },
+
+    #[event_version("1.0.0")]
+    Deposit {
+        sender_id: AccountId,
+        amount: U128,
+        available_rewards: U128
+    }
+}
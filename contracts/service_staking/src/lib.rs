@@ -0,0 +1,554 @@
+use near_contract_standards::fungible_token::{core::ext_ft_core, receiver::FungibleTokenReceiver};
+use near_contract_standards::non_fungible_token::core::{ext_nft_core, NonFungibleTokenReceiver};
+use near_contract_standards::non_fungible_token::TokenId;
+use near_sdk::borsh::BorshSerialize;
+use near_sdk::json_types::U128;
+use near_sdk::serde_json::{self, json};
+use near_sdk::{
+    env, near, AccountId, BorshStorageKey, PanicOnDefault, Promise, PromiseOrValue, PromiseResult, Gas,
+    PromiseError, NearToken
+};
+use near_sdk::store::IterableMap;
+
+mod errors;
+use errors::{ensure, StakingError};
+mod events;
+use events::StakingEvent;
+
+// 256-bit unsigned integer for intermediate reward calculations
+mod u256 {
+    // Lints of the macro expansion are out of the contract code
+    #![allow(clippy::all)]
+    uint::construct_uint! {
+        pub struct U256(4);
+    }
+}
+use u256::U256;
+
+// Service agent params returned by the ServiceRegistry get_service view
+#[near(serializers=[json])]
+pub struct RegistryAgentParams {
+    pub agent_id: u32,
+    pub num_agent_instances: u32,
+    pub bond: U128
+}
+
+// Service returned by the ServiceRegistry get_service view, only with the fields checked for staking
+#[near(serializers=[json])]
+pub struct RegistryService {
+    pub token: Option<AccountId>,
+    pub multisig: Option<AccountId>,
+    pub threshold: u32,
+    pub state: String,
+    pub agent_params: Vec<RegistryAgentParams>
+}
+
+#[near(serializers=[borsh, json])]
+#[derive(Clone)]
+pub struct StakingParams {
+    // Required service agent ids, any agent ids if empty
+    pub agent_ids: Vec<u32>,
+    // Required service multisig threshold, any threshold if zero
+    pub threshold: u32,
+    // Required number of service agent instances
+    pub num_agent_instances: u32,
+    // Minimum bond of each service agent instance
+    pub min_staking_deposit: U128,
+    // Required service token, native NEAR if none
+    pub staking_token: Option<AccountId>,
+    // Maximum number of staked services
+    pub max_num_services: u32,
+    // Rewards per second for each eligible service
+    pub rewards_per_second: U128,
+    // Minimum staking duration in seconds
+    pub min_staking_duration: u64,
+    // Liveness period in seconds
+    pub liveness_period: u64,
+    // Required number of multisig requests per second, multiplied by LIVENESS_RATIO_DECIMALS
+    pub liveness_ratio: U128,
+    // Reward token, native NEAR if none
+    pub reward_token: Option<AccountId>
+}
+
+#[near(serializers=[borsh, json])]
+#[derive(Clone)]
+pub struct ServiceInfo {
+    // Service owner that receives the service NFT back on unstake
+    pub owner_id: AccountId,
+    // Service multisig that receives rewards
+    pub multisig: AccountId,
+    // Staking start time in seconds
+    pub ts_start: u64,
+    // Multisig request nonce at the last checkpoint
+    pub nonce: u32,
+    // Accumulated reward
+    pub reward: U128
+}
+
+// Deployed service state in the ServiceRegistry
+const SERVICE_STATE_DEPLOYED: &str = "Deployed";
+// Liveness ratio precision
+const LIVENESS_RATIO_DECIMALS: u128 = 1_000_000_000_000_000_000;
+const NANOSECONDS: u64 = 1_000_000_000;
+const CALL_GAS: Gas = Gas::from_tgas(5);
+const CALLBACK_GAS: Gas = Gas::from_tgas(20);
+const NFT_TRANSFER_GAS: Gas = Gas::from_tgas(15);
+
+#[near(contract_state)]
+#[derive(PanicOnDefault)]
+pub struct ServiceStaking {
+    service_registry: AccountId,
+    params: StakingParams,
+    services: IterableMap<u32, ServiceInfo>,
+    // Rewards available for distribution
+    available_rewards: u128,
+    // Last checkpoint time in seconds
+    ts_checkpoint: u64,
+    epoch_counter: u64
+}
+
+#[derive(BorshStorageKey, BorshSerialize)]
+#[borsh(crate = "near_sdk::borsh")]
+enum StorageKey {
+    Service
+}
+
+fn block_timestamp_secs() -> u64 {
+    env::block_timestamp() / NANOSECONDS
+}
+
+// Calls the view method of another contract with JSON arguments
+fn view_call(account_id: AccountId, method_name: &str, args: serde_json::Value) -> Promise {
+    Promise::new(account_id).function_call(
+        method_name.to_string(),
+        args.to_string().into_bytes(),
+        NearToken::from_yoctonear(0),
+        CALL_GAS
+    )
+}
+
+// Gets the multisig2 request nonce
+fn get_request_nonce(multisig: AccountId) -> Promise {
+    view_call(multisig, "get_request_nonce", json!({}))
+}
+
+#[near]
+impl ServiceStaking {
+    /// Initializes the contract
+    #[init]
+    pub fn new(service_registry: AccountId, params: StakingParams) -> Self {
+        assert!(!env::state_exists(), "Already initialized");
+
+        // Check staking parameters
+        ensure(params.num_agent_instances > 0, StakingError::WrongStakingParams);
+        ensure(params.max_num_services > 0, StakingError::WrongStakingParams);
+        ensure(params.rewards_per_second.0 > 0, StakingError::WrongStakingParams);
+        ensure(params.min_staking_deposit.0 > 0, StakingError::WrongStakingParams);
+        ensure(params.liveness_period > 0, StakingError::WrongStakingParams);
+        ensure(params.threshold <= params.num_agent_instances, StakingError::WrongStakingParams);
+
+        Self {
+            service_registry,
+            params,
+            services: IterableMap::new(StorageKey::Service),
+            available_rewards: 0,
+            ts_checkpoint: block_timestamp_secs(),
+            epoch_counter: 0
+        }
+    }
+
+    // Checks the service against the staking parameters
+    fn check_service(&self, service: &RegistryService) -> Result<(), &'static str> {
+        if service.state != SERVICE_STATE_DEPLOYED {
+            return Err("Service is not deployed");
+        }
+        if service.token != self.params.staking_token {
+            return Err("Wrong service token");
+        }
+        let agent_ids: Vec<u32> = service.agent_params.iter().map(|params| params.agent_id).collect();
+        if !self.params.agent_ids.is_empty() && agent_ids != self.params.agent_ids {
+            return Err("Wrong service agent ids");
+        }
+        if self.params.threshold > 0 && service.threshold != self.params.threshold {
+            return Err("Wrong service threshold");
+        }
+        if service.agent_params.iter().map(|params| params.num_agent_instances).sum::<u32>() != self.params.num_agent_instances {
+            return Err("Wrong number of service agent instances");
+        }
+        if service.agent_params.iter().any(|params| params.bond.0 < self.params.min_staking_deposit.0) {
+            return Err("Insufficient service bonds");
+        }
+        if self.services.len() >= self.params.max_num_services {
+            return Err("Maximum number of services is reached");
+        }
+        Ok(())
+    }
+
+    #[private]
+    pub fn stake_callback(
+        &mut self,
+        service_id: u32,
+        owner_id: AccountId,
+        #[callback_result] service: Result<Option<RegistryService>, PromiseError>,
+    ) -> PromiseOrValue<bool> {
+        // Return the service NFT if the service data is not available
+        let Ok(Some(service)) = service else {
+            env::log_str("Service data is not available");
+            return PromiseOrValue::Value(true);
+        };
+
+        // Return the service NFT if it does not satisfy staking parameters
+        if let Err(reason) = self.check_service(&service) {
+            env::log_str(reason);
+            return PromiseOrValue::Value(true);
+        }
+        let Some(multisig) = service.multisig else {
+            env::log_str("Service is not deployed");
+            return PromiseOrValue::Value(true);
+        };
+
+        // Get the current multisig nonce to start tracking the service liveness from
+        PromiseOrValue::Promise(get_request_nonce(multisig.clone())
+            .then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(CALL_GAS)
+                    .stake_finalize(service_id, owner_id, multisig)
+            ))
+    }
+
+    #[private]
+    pub fn stake_finalize(
+        &mut self,
+        service_id: u32,
+        owner_id: AccountId,
+        multisig: AccountId,
+        #[callback_result] nonce: Result<u32, PromiseError>,
+    ) -> bool {
+        // Return the service NFT if the multisig nonce is not available
+        let Ok(nonce) = nonce else {
+            env::log_str("Multisig nonce is not available");
+            return true;
+        };
+
+        // Other services could have been staked in the meantime
+        if self.services.len() >= self.params.max_num_services {
+            env::log_str("Maximum number of services is reached");
+            return true;
+        }
+
+        self.services.insert(service_id, ServiceInfo {
+            owner_id: owner_id.clone(),
+            multisig: multisig.clone(),
+            ts_start: block_timestamp_secs(),
+            nonce,
+            reward: U128::from(0)
+        });
+        self.services.flush();
+
+        StakingEvent::ServiceStaked { service_id, owner_id, multisig, nonce }.emit();
+
+        // The service NFT is kept
+        false
+    }
+
+    /// Checks the liveness of all staked services and distributes rewards for the passed period
+    pub fn checkpoint(&mut self) -> PromiseOrValue<bool> {
+        let ts_now = block_timestamp_secs();
+        let ts_previous = self.ts_checkpoint;
+
+        // Checkpoint is allowed once per liveness period
+        ensure(ts_now - ts_previous >= self.params.liveness_period, StakingError::CheckpointNotDue);
+
+        let mut service_ids = Vec::new();
+        let mut promise: Option<Promise> = None;
+        for (service_id, info) in self.services.iter() {
+            service_ids.push(*service_id);
+
+            // Get the multisig nonce of each service
+            let nonce_promise = get_request_nonce(info.multisig.clone());
+            promise = Some(match promise {
+                Some(p) => p.and(nonce_promise),
+                None => nonce_promise
+            });
+        }
+
+        match promise {
+            Some(p) => PromiseOrValue::Promise(p.then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(CALLBACK_GAS)
+                    .checkpoint_callback(service_ids, ts_previous, ts_now)
+            )),
+            None => {
+                // No services are staked
+                self.ts_checkpoint = ts_now;
+                self.epoch_counter += 1;
+                StakingEvent::Checkpoint {
+                    epoch: self.epoch_counter,
+                    available_rewards: U128::from(self.available_rewards),
+                    service_ids: Vec::new(),
+                    rewards: Vec::new()
+                }.emit();
+                PromiseOrValue::Value(true)
+            }
+        }
+    }
+
+    #[private]
+    pub fn checkpoint_callback(&mut self, service_ids: Vec<u32>, ts_previous: u64, ts_now: u64) -> bool {
+        // Another checkpoint started from the same time could have been finished in the meantime
+        if self.ts_checkpoint != ts_previous {
+            env::log_str("Checkpoint is already done");
+            return false;
+        }
+
+        // The checkpoint time is recorded only when the checkpoint is finished, such that a failed one can be repeated
+        self.ts_checkpoint = ts_now;
+
+        let mut eligible_service_ids = Vec::new();
+        let mut eligible_rewards = Vec::new();
+        // Total rewards are summed up in 256 bits, such that neither the sum nor its scaling overflows
+        let mut total_rewards = U256::zero();
+
+        for (i, service_id) in service_ids.iter().enumerate() {
+            // Get the multisig nonce
+            let nonce = match env::promise_result(i as u64) {
+                PromiseResult::Successful(value) => serde_json::from_slice::<u32>(&value).ok(),
+                _ => None
+            };
+
+            // The service could have been unstaked in the meantime
+            let Some(info) = self.services.get_mut(service_id) else { continue };
+            let Some(nonce) = nonce else { continue };
+
+            // Services staked after the previous checkpoint are only checked from their staking time
+            let ts_start = info.ts_start.max(ts_previous);
+            if ts_now > ts_start {
+                let duration = (ts_now - ts_start) as u128;
+                // Number of multisig requests per second
+                let ratio = (nonce.saturating_sub(info.nonce) as u128) * LIVENESS_RATIO_DECIMALS / duration;
+                if ratio >= self.params.liveness_ratio.0 {
+                    let reward = self.params.rewards_per_second.0.saturating_mul(duration);
+                    eligible_service_ids.push(*service_id);
+                    eligible_rewards.push(reward);
+                    total_rewards += U256::from(reward);
+                }
+            }
+
+            info.nonce = nonce;
+        }
+
+        // Scale rewards down proportionally if there are not enough available rewards
+        if total_rewards > U256::from(self.available_rewards) {
+            for reward in eligible_rewards.iter_mut() {
+                *reward = (U256::from(*reward) * U256::from(self.available_rewards) / total_rewards).as_u128();
+            }
+        }
+
+        // Distribute rewards
+        for (service_id, reward) in eligible_service_ids.iter().zip(eligible_rewards.iter()) {
+            let info = self.services.get_mut(service_id).unwrap();
+            info.reward = U128::from(info.reward.0 + reward);
+            self.available_rewards -= reward;
+        }
+        self.services.flush();
+
+        self.epoch_counter += 1;
+
+        StakingEvent::Checkpoint {
+            epoch: self.epoch_counter,
+            available_rewards: U128::from(self.available_rewards),
+            service_ids: eligible_service_ids,
+            rewards: eligible_rewards.into_iter().map(U128::from).collect()
+        }.emit();
+
+        true
+    }
+
+    fn transfer_reward(&self, receiver_id: AccountId, amount: u128) {
+        if amount == 0 {
+            return;
+        }
+
+        match self.params.reward_token.clone() {
+            None => {
+                Promise::new(receiver_id).transfer(NearToken::from_yoctonear(amount));
+            }
+            Some(token) => {
+                ext_ft_core::ext(token)
+                    .with_attached_deposit(NearToken::from_yoctonear(1))
+                    .with_static_gas(CALL_GAS)
+                    .ft_transfer(receiver_id, U128::from(amount), None)
+                    .then(
+                        Self::ext(env::current_account_id())
+                            .with_static_gas(CALL_GAS)
+                            .resolve_reward_transfer(U128::from(amount))
+                    );
+            }
+        }
+    }
+
+    #[private]
+    pub fn resolve_reward_transfer(
+        &mut self,
+        amount: U128,
+        #[callback_result] call_result: Result<(), PromiseError>,
+    ) {
+        // Return the reward into the pool if the transfer has failed
+        if call_result.is_err() {
+            self.available_rewards += amount.0;
+        }
+    }
+
+    /// Sends the accumulated reward to the service multisig
+    // Call by the service owner
+    pub fn claim(&mut self, service_id: u32) -> U128 {
+        let info = self.services.get_mut(&service_id).unwrap_or_else(|| StakingError::ServiceNotStaked.panic());
+        ensure(env::predecessor_account_id() == info.owner_id, StakingError::Unauthorized);
+
+        let reward = info.reward.0;
+        ensure(reward > 0, StakingError::ZeroReward);
+        info.reward = U128::from(0);
+
+        let owner_id = info.owner_id.clone();
+        let multisig = info.multisig.clone();
+        self.services.flush();
+
+        self.transfer_reward(multisig.clone(), reward);
+
+        StakingEvent::RewardClaimed { service_id, owner_id, multisig, amount: U128::from(reward) }.emit();
+
+        U128::from(reward)
+    }
+
+    /// Sends the accumulated reward to the service multisig and returns the service NFT to its owner
+    // Call by the service owner
+    pub fn unstake(&mut self, service_id: u32) -> U128 {
+        let info = self.services.get(&service_id).unwrap_or_else(|| StakingError::ServiceNotStaked.panic()).clone();
+        ensure(env::predecessor_account_id() == info.owner_id, StakingError::Unauthorized);
+
+        // The service can leave earlier than the minimum staking duration only if there are no rewards left
+        let ts_now = block_timestamp_secs();
+        ensure(ts_now - info.ts_start >= self.params.min_staking_duration || self.available_rewards == 0,
+            StakingError::MinStakingDurationNotReached);
+
+        self.services.remove(&service_id);
+        self.services.flush();
+
+        self.transfer_reward(info.multisig.clone(), info.reward.0);
+
+        // Return the service NFT
+        ext_nft_core::ext(self.service_registry.clone())
+            .with_attached_deposit(NearToken::from_yoctonear(1))
+            .with_static_gas(NFT_TRANSFER_GAS)
+            .nft_transfer(info.owner_id.clone(), service_id.to_string(), None, None);
+
+        StakingEvent::ServiceUnstaked {
+            service_id,
+            owner_id: info.owner_id,
+            multisig: info.multisig,
+            reward: info.reward
+        }.emit();
+
+        info.reward
+    }
+
+    /// Deposits native NEAR rewards
+    #[payable]
+    pub fn deposit(&mut self) {
+        ensure(self.params.reward_token.is_none(), StakingError::WrongRewardToken);
+
+        let amount = env::attached_deposit().as_yoctonear();
+        ensure(amount > 0, StakingError::ZeroDeposit);
+        self.available_rewards += amount;
+
+        StakingEvent::Deposit {
+            sender_id: env::predecessor_account_id(),
+            amount: U128::from(amount),
+            available_rewards: U128::from(self.available_rewards)
+        }.emit();
+    }
+
+    pub fn get_staking_params(&self) -> StakingParams {
+        self.params.clone()
+    }
+
+    pub fn get_service_registry(&self) -> AccountId {
+        self.service_registry.clone()
+    }
+
+    pub fn get_service_info(&self, service_id: u32) -> Option<ServiceInfo> {
+        self.services.get(&service_id).cloned()
+    }
+
+    pub fn get_service_ids(&self) -> Vec<u32> {
+        self.services.keys().cloned().collect()
+    }
+
+    pub fn get_available_rewards(&self) -> U128 {
+        U128::from(self.available_rewards)
+    }
+
+    pub fn get_next_checkpoint_ts(&self) -> u64 {
+        self.ts_checkpoint + self.params.liveness_period
+    }
+
+    pub fn get_epoch_counter(&self) -> u64 {
+        self.epoch_counter
+    }
+
+    pub fn version(&self) -> String {
+        env!("CARGO_PKG_VERSION").to_owned()
+    }
+}
+
+#[near]
+impl NonFungibleTokenReceiver for ServiceStaking {
+    fn nft_on_transfer(
+        &mut self,
+        sender_id: AccountId,
+        previous_owner_id: AccountId,
+        token_id: TokenId,
+        msg: String,
+    ) -> PromiseOrValue<bool> {
+        let _ = (sender_id, msg);
+
+        // Only service NFTs are accepted
+        ensure(env::predecessor_account_id() == self.service_registry, StakingError::WrongServiceRegistry);
+        let service_id: u32 = token_id.parse().unwrap_or_else(|_| StakingError::WrongServiceId.panic());
+        ensure(self.services.len() < self.params.max_num_services, StakingError::MaxNumServicesReached);
+
+        // Get the service data from the service registry
+        PromiseOrValue::Promise(view_call(self.service_registry.clone(), "get_service", json!({"service_id": service_id}))
+            .then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(CALLBACK_GAS)
+                    .stake_callback(service_id, previous_owner_id)
+            ))
+    }
+}
+
+#[near]
+impl FungibleTokenReceiver for ServiceStaking {
+    fn ft_on_transfer(
+        &mut self,
+        sender_id: AccountId,
+        amount: U128,
+        msg: String,
+    ) -> PromiseOrValue<U128> {
+        let _ = msg;
+
+        // Only reward tokens are accepted
+        ensure(self.params.reward_token == Some(env::predecessor_account_id()), StakingError::WrongRewardToken);
+        ensure(amount.0 > 0, StakingError::ZeroDeposit);
+        self.available_rewards += amount.0;
+
+        StakingEvent::Deposit {
+            sender_id,
+            amount,
+            available_rewards: U128::from(self.available_rewards)
+        }.emit();
+
+        // No tokens will be returned
+        PromiseOrValue::Value(U128::from(0))
+    }
+}
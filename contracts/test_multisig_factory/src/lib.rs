@@ -0,0 +1,126 @@
+use near_sdk::json_types::Base64VecU8;
+use near_sdk::serde_json::{self, json, Value};
+use near_sdk::{env, near, require, AccountId, PanicOnDefault, Promise, PublicKey, Gas, PromiseError};
+
+// Test multisig factory mimicking the multisig factory interface
+// It creates multisig accounts without the multisig code and can be set to fail the creation
+// It also accepts the Sputnik DAO factory arguments, such that it can be approved as a DAO factory
+// When deployed on a multisig account, it mimics the multisig2 views with the preset members and request nonce,
+// and the Sputnik DAO policy view with account members as the council
+
+// Members are untagged in JSON the same way as in the multisig factory
+#[near(serializers=[borsh, json])]
+#[serde(untagged)]
+#[derive(Clone)]
+pub enum MultisigMember {
+    AccessKey { public_key: PublicKey },
+    Account { account_id: AccountId },
+}
+
+const CALL_GAS: Gas = Gas::from_tgas(5);
+
+#[near(contract_state)]
+#[derive(PanicOnDefault)]
+pub struct TestMultisigFactory {
+    fail: bool,
+    members: Vec<MultisigMember>,
+    num_confirmations: u32,
+    request_nonce: u32
+}
+
+#[near]
+impl TestMultisigFactory {
+    #[init]
+    pub fn new() -> Self {
+        Self { fail: false, members: Vec::new(), num_confirmations: 0, request_nonce: 0 }
+    }
+
+    /// Sets members and the number of confirmations returned by multisig views
+    pub fn set_members(&mut self, members: Vec<MultisigMember>, num_confirmations: u32) {
+        self.members = members;
+        self.num_confirmations = num_confirmations;
+    }
+
+    pub fn get_members(&self) -> Vec<MultisigMember> {
+        self.members.clone()
+    }
+
+    pub fn get_num_confirmations(&self) -> u32 {
+        self.num_confirmations
+    }
+
+    /// Returns the Sputnik DAO policy with account members as the council and the number of confirmations as the threshold
+    pub fn get_policy(&self) -> Value {
+        let council: Vec<AccountId> = self.members
+            .iter()
+            .filter_map(|member| match member {
+                MultisigMember::Account { account_id } => Some(account_id.clone()),
+                MultisigMember::AccessKey { .. } => None
+            })
+            .collect();
+        json!({
+            "roles": [{"name": "council", "kind": {"Group": council}, "permissions": ["*:*"], "vote_policy": {}}],
+            "default_vote_policy": {
+                "weight_kind": "RoleWeight",
+                "quorum": "0",
+                "threshold": self.num_confirmations.to_string()
+            },
+            "proposal_bond": "1000000000000000000000000",
+            "proposal_period": "604800000000000",
+            "bounty_bond": "1000000000000000000000000",
+            "bounty_forgiveness_period": "86400000000000"
+        })
+    }
+
+    /// Sets the request nonce returned by the multisig view
+    pub fn set_request_nonce(&mut self, request_nonce: u32) {
+        self.request_nonce = request_nonce;
+    }
+
+    pub fn get_request_nonce(&self) -> u32 {
+        self.request_nonce
+    }
+
+    /// Sets the factory to fail all the subsequent multisig creations
+    pub fn set_fail(&mut self, fail: bool) {
+        self.fail = fail;
+    }
+
+    #[payable]
+    pub fn create(
+        &mut self,
+        name: AccountId,
+        members: Option<Vec<MultisigMember>>,
+        num_confirmations: Option<u64>,
+        args: Option<Base64VecU8>,
+    ) -> Promise {
+        require!(!self.fail, "Multisig creation failed");
+        match args {
+            // Sputnik DAO factory arguments must contain the DAO config and policy
+            Some(args) => {
+                let args: Value = serde_json::from_slice(&args.0).expect("Wrong DAO arguments");
+                require!(args["config"].is_object() && args["policy"]["roles"].is_array(), "Wrong DAO arguments");
+            }
+            None => {
+                let members = members.expect("No members");
+                let num_confirmations = num_confirmations.expect("No number of confirmations");
+                require!(num_confirmations > 0 && num_confirmations as usize <= members.len(), "Wrong number of confirmations");
+            }
+        }
+
+        let account_id: AccountId = format!("{}.{}", name, env::current_account_id()).parse().unwrap();
+        Promise::new(account_id)
+            .create_account()
+            .transfer(env::attached_deposit())
+            .then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(CALL_GAS)
+                    .on_create()
+            )
+    }
+
+    #[private]
+    pub fn on_create(&mut self, #[callback_result] call_result: Result<(), PromiseError>) -> bool {
+        call_result.is_ok()
+    }
+}
@@ -0,0 +1,49 @@
+use near_sdk::env;
+
+/// Unit Registry errors with stable codes.
+/// Each error panics with the `E<code>: <message>` string, e.g. `E002: Unit not found`
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum UnitError {
+    Unauthorized = 1,
+    UnitNotFound = 2,
+    WrongComponentRegistry = 3,
+    InsufficientDeposit = 4,
+    WrongNumberOfCopies = 5,
+    ZeroUnitHash = 6,
+    WrongDependenciesOrder = 7,
+    DependencyNotFound = 8,
+    NoDependencies = 9,
+    ComponentRegistryCheckFailed = 10
+}
+
+impl UnitError {
+    pub fn code(&self) -> u32 {
+        *self as u32
+    }
+
+    pub fn message(&self) -> &'static str {
+        match self {
+            UnitError::Unauthorized => "Unauthorized",
+            UnitError::UnitNotFound => "Unit not found",
+            UnitError::WrongComponentRegistry => "Wrong component registry setup",
+            UnitError::InsufficientDeposit => "Insufficient deposit",
+            UnitError::WrongNumberOfCopies => "Number of copies must be equal to one",
+            UnitError::ZeroUnitHash => "Zero unit hash",
+            UnitError::WrongDependenciesOrder => "Wrong dependencies order",
+            UnitError::DependencyNotFound => "Dependency not found",
+            UnitError::NoDependencies => "No dependencies",
+            UnitError::ComponentRegistryCheckFailed => "Component registry check failed"
+        }
+    }
+
+    pub fn panic(self) -> ! {
+        env::panic_str(&format!("E{:03}: {}", self.code(), self.message()))
+    }
+}
+
+/// Panics with the provided error if the condition does not hold
+pub fn ensure(condition: bool, error: UnitError) {
+    if !condition {
+        error.panic();
+    }
+}
@@ -0,0 +1,26 @@
+use near_sdk::{near, AccountId};
+
+use crate::UnitType;
+
+/// Unit Registry events following the NEP-297 standard.
+/// Each event is logged as `EVENT_JSON:{"standard":"olas_unit_registry","version":...,"event":...,"data":...}`
+#[near(event_json(standard = "olas_unit_registry"))]
+pub enum UnitEvent {
+    #[event_version("1.0.0")]
+    CreateUnit {
+        unit_id: u32,
+        unit_type: UnitType,
+        owner_id: AccountId,
+        // Hex encoded unit hash
+        unit_hash: String,
+        dependencies: Vec<u32>
+    },
+
+    #[event_version("1.0.0")]
+    UpdateUnitHash {
+        unit_id: u32,
+        unit_type: UnitType,
+        // Hex encoded unit hash
+        unit_hash: String
+    }
+}
@@ -0,0 +1,329 @@
+use near_contract_standards::non_fungible_token::metadata::{
+    NFTContractMetadata, TokenMetadata, NonFungibleTokenMetadataProvider
+};
+use near_contract_standards::non_fungible_token::{NonFungibleToken, Token, TokenId};
+use near_contract_standards::non_fungible_token::enumeration::ext_nft_enumeration;
+use near_sdk::borsh::BorshSerialize;
+use near_sdk::json_types::U128;
+use near_sdk::{
+    env, near, AccountId, BorshStorageKey, PanicOnDefault, Promise, PromiseOrValue, Gas, PromiseError, NearToken
+};
+use near_sdk::store::{LookupMap, Vector};
+
+mod errors;
+use errors::{ensure, UnitError};
+mod events;
+use events::UnitEvent;
+
+#[near(serializers=[borsh, json])]
+#[derive(PartialEq, Clone)]
+pub enum UnitType {
+    Component,
+    Agent
+}
+
+#[near(serializers=[borsh])]
+pub struct Unit {
+    // IPFS hashes pointing to the unit metadata, the last one being the current one
+    pub unit_hashes: Vector<[u8; 32]>,
+    // Sorted set of component Ids the unit depends on
+    pub dependencies: Vec<u32>
+}
+
+#[near(serializers=[json])]
+pub struct UnitView {
+    pub unit_id: u32,
+    pub unit_type: UnitType,
+    pub owner_id: AccountId,
+    // Current unit hash
+    pub unit_hash: [u8; 32],
+    pub dependencies: Vec<u32>
+}
+
+// Unit parameters passed to the create callback
+#[near(serializers=[json])]
+pub struct UnitParams {
+    pub unit_owner: AccountId,
+    pub metadata: TokenMetadata,
+    pub unit_hash: [u8; 32],
+    // Sorted set of component Ids the unit depends on
+    pub dependencies: Vec<u32>
+}
+
+const CALL_GAS: Gas = Gas::from_tgas(5);
+const CREATE_CALLBACK_GAS: Gas = Gas::from_tgas(50);
+
+#[near(contract_state)]
+#[derive(PanicOnDefault)]
+pub struct UnitRegistry {
+    unit_type: UnitType,
+    // Registry of components the agent dependencies refer to, none for the component registry itself
+    component_registry: Option<AccountId>,
+    units: LookupMap<u32, Unit>,
+    tokens: NonFungibleToken,
+    metadata: Option<NFTContractMetadata>
+}
+
+#[derive(BorshStorageKey, BorshSerialize)]
+#[borsh(crate = "near_sdk::borsh")]
+enum StorageKey {
+    NonFungibleToken,
+    TokenMetadata,
+    Enumeration,
+    Approval,
+    Unit,
+    UnitHash { unit_id: u32 }
+}
+
+#[near]
+impl UnitRegistry {
+    /// Initializes the contract either as a component registry, or as an agent registry
+    /// whose agents depend on components from the provided component registry
+    #[init]
+    pub fn new(unit_type: UnitType, component_registry: Option<AccountId>, metadata: NFTContractMetadata) -> Self {
+        assert!(!env::state_exists(), "Already initialized");
+        metadata.assert_valid();
+
+        // Only agent registry refers to a component registry
+        ensure((unit_type == UnitType::Agent) == component_registry.is_some(), UnitError::WrongComponentRegistry);
+
+        Self {
+            unit_type,
+            component_registry,
+            units: LookupMap::new(StorageKey::Unit),
+            tokens: NonFungibleToken::new(
+                StorageKey::NonFungibleToken,
+                env::current_account_id(),
+                Some(StorageKey::TokenMetadata),
+                Some(StorageKey::Enumeration),
+                Some(StorageKey::Approval),
+            ),
+            metadata: Some(metadata)
+        }
+    }
+
+    fn refund_deposit_to_account(&self, storage_used: u64, account_id: AccountId, deposit: NearToken) {
+        let required_cost = env::storage_byte_cost().saturating_mul(storage_used.into());
+
+        // Required cost must not be bigger than the deposit
+        ensure(required_cost <= deposit, UnitError::InsufficientDeposit);
+        let refund = deposit.saturating_sub(required_cost);
+        if refund.as_yoctonear() > 1 {
+            Promise::new(account_id).transfer(refund);
+        }
+    }
+
+    fn internal_create(&mut self, params: UnitParams, depositor: AccountId, deposit: NearToken) -> u32 {
+        let UnitParams { unit_owner, metadata, unit_hash, dependencies } = params;
+
+        // Record current storage usage
+        let initial_storage_usage = env::storage_usage();
+
+        // To be consistent with EVM where Ids start from 1, each new unit Id is equal to supply + 1
+        let unit_id = self.tokens.owner_by_id.len() as u32 + 1;
+
+        // Mint new unit
+        self.tokens.internal_mint_with_refund(unit_id.to_string(), unit_owner.clone(), Some(metadata), None);
+
+        // Record the first unit hash
+        let mut unit_hashes = Vector::new(StorageKey::UnitHash { unit_id });
+        unit_hashes.push(unit_hash);
+        unit_hashes.flush();
+
+        self.units.insert(unit_id, Unit { unit_hashes, dependencies: dependencies.clone() });
+        self.units.flush();
+
+        UnitEvent::CreateUnit {
+            unit_id,
+            unit_type: self.unit_type.clone(),
+            owner_id: unit_owner,
+            unit_hash: hex::encode(unit_hash),
+            dependencies
+        }.emit();
+
+        // Pay for the storage and refund excessive amount
+        let storage = env::storage_usage() - initial_storage_usage;
+        self.refund_deposit_to_account(storage, depositor, deposit);
+
+        unit_id
+    }
+
+    /// Creates a unit with its metadata hash and the sorted list of component dependencies
+    #[payable]
+    pub fn create(
+        &mut self,
+        unit_owner: AccountId,
+        metadata: TokenMetadata,
+        unit_hash: [u8; 32],
+        dependencies: Vec<u32>
+    ) -> PromiseOrValue<u32> {
+        // Number of copies must be equal to one
+        ensure(metadata.copies == Some(1), UnitError::WrongNumberOfCopies);
+
+        // Check unit hash
+        ensure(!unit_hash.iter().all(|h| *h == 0), UnitError::ZeroUnitHash);
+
+        // Check that dependencies are non-zero, sorted and not repeated
+        let mut last_id = 0;
+        for id in dependencies.iter() {
+            ensure(*id > last_id, UnitError::WrongDependenciesOrder);
+            last_id = *id;
+        }
+
+        let params = UnitParams { unit_owner, metadata, unit_hash, dependencies };
+        match self.component_registry.clone() {
+            None => {
+                // Components can only depend on already existing components
+                ensure(last_id as u64 <= self.tokens.owner_by_id.len(), UnitError::DependencyNotFound);
+
+                PromiseOrValue::Value(self.internal_create(params, env::predecessor_account_id(), env::attached_deposit()))
+            }
+            Some(component_registry) => {
+                // Agents must depend on at least one component
+                ensure(last_id > 0, UnitError::NoDependencies);
+
+                let depositor = env::predecessor_account_id();
+                let deposit = U128::from(env::attached_deposit().as_yoctonear());
+
+                // Get the number of existing components
+                PromiseOrValue::Promise(ext_nft_enumeration::ext(component_registry)
+                    .with_static_gas(CALL_GAS)
+                    .nft_total_supply()
+                    .then(
+                        Self::ext(env::current_account_id())
+                            .with_static_gas(CREATE_CALLBACK_GAS)
+                            .create_callback(params, depositor.clone(), deposit)
+                    )
+                    .then(
+                        Self::ext(env::current_account_id())
+                            .with_static_gas(CALL_GAS)
+                            .resolve_deposit(depositor, deposit)
+                    ))
+            }
+        }
+    }
+
+    #[private]
+    pub fn create_callback(
+        &mut self,
+        params: UnitParams,
+        depositor: AccountId,
+        deposit: U128,
+        #[callback_result] call_result: Result<U128, PromiseError>,
+    ) -> u32 {
+        let num_components = call_result.unwrap_or_else(|_| UnitError::ComponentRegistryCheckFailed.panic());
+
+        // Agent dependencies must be existing components
+        let last_id = *params.dependencies.last().unwrap();
+        ensure(last_id as u128 <= num_components.0, UnitError::DependencyNotFound);
+
+        self.internal_create(params, depositor, NearToken::from_yoctonear(deposit.0))
+    }
+
+    #[private]
+    pub fn resolve_deposit(
+        &self,
+        depositor: AccountId,
+        deposit: U128,
+        #[callback_result] call_result: Result<u32, PromiseError>,
+    ) -> u32 {
+        match call_result {
+            Ok(unit_id) => unit_id,
+            Err(_) => {
+                // Refund the full deposit if the unit creation has failed
+                if deposit.0 > 0 {
+                    Promise::new(depositor).transfer(NearToken::from_yoctonear(deposit.0));
+                }
+                0
+            }
+        }
+    }
+
+    /// Updates the unit hash, the previous hashes are kept in the unit history
+    // Call by the unit owner
+    #[payable]
+    pub fn update_hash(&mut self, unit_id: u32, unit_hash: [u8; 32]) {
+        // Record current storage usage
+        let initial_storage_usage = env::storage_usage();
+
+        // Check for unit owner
+        let owner_id = self.tokens
+            .owner_by_id
+            .get(&unit_id.to_string())
+            .unwrap_or_else(|| UnitError::UnitNotFound.panic());
+        ensure(env::predecessor_account_id() == owner_id, UnitError::Unauthorized);
+
+        // Check unit hash
+        ensure(!unit_hash.iter().all(|h| *h == 0), UnitError::ZeroUnitHash);
+
+        // Record the new unit hash
+        let unit = self.units.get_mut(&unit_id).unwrap();
+        unit.unit_hashes.push(unit_hash);
+        unit.unit_hashes.flush();
+        self.units.flush();
+
+        UnitEvent::UpdateUnitHash {
+            unit_id,
+            unit_type: self.unit_type.clone(),
+            unit_hash: hex::encode(unit_hash)
+        }.emit();
+
+        // Pay for the storage and refund excessive amount
+        let storage = env::storage_usage() - initial_storage_usage;
+        self.refund_deposit_to_account(storage, env::predecessor_account_id(), env::attached_deposit());
+    }
+
+    pub fn get_unit(&self, unit_id: u32) -> Option<UnitView> {
+        let owner_id = self.tokens.owner_by_id.get(&unit_id.to_string())?;
+        let unit = self.units.get(&unit_id)?;
+
+        Some(UnitView {
+            unit_id,
+            unit_type: self.unit_type.clone(),
+            owner_id,
+            unit_hash: *unit.unit_hashes.iter().last().unwrap(),
+            dependencies: unit.dependencies.clone()
+        })
+    }
+
+    pub fn get_unit_hashes(&self, unit_id: u32) -> Vec<[u8; 32]> {
+        self.units.get(&unit_id).unwrap_or_else(|| UnitError::UnitNotFound.panic()).unit_hashes.iter().cloned().collect()
+    }
+
+    pub fn get_dependencies(&self, unit_id: u32) -> Vec<u32> {
+        self.units.get(&unit_id).unwrap_or_else(|| UnitError::UnitNotFound.panic()).dependencies.clone()
+    }
+
+    /// Returns true if all the provided unit Ids exist
+    pub fn units_exist(&self, unit_ids: Vec<u32>) -> bool {
+        let supply = self.tokens.owner_by_id.len();
+        unit_ids.into_iter().all(|id| id > 0 && id as u64 <= supply)
+    }
+
+    pub fn get_unit_type(&self) -> UnitType {
+        self.unit_type.clone()
+    }
+
+    pub fn get_component_registry(&self) -> Option<AccountId> {
+        self.component_registry.clone()
+    }
+
+    pub fn total_supply(&self) -> U128 {
+        self.tokens.nft_total_supply()
+    }
+
+    pub fn version(&self) -> String {
+        env!("CARGO_PKG_VERSION").to_owned()
+    }
+}
+
+near_contract_standards::impl_non_fungible_token_core!(UnitRegistry, tokens);
+near_contract_standards::impl_non_fungible_token_approval!(UnitRegistry, tokens);
+near_contract_standards::impl_non_fungible_token_enumeration!(UnitRegistry, tokens);
+
+#[near]
+impl NonFungibleTokenMetadataProvider for UnitRegistry {
+    fn nft_metadata(&self) -> NFTContractMetadata {
+         self.metadata.clone().unwrap()
+    }
+}
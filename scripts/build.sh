@@ -10,7 +10,7 @@ fi
 # cargo clean
 rm -rf artifacts/${TARGET}
 
-cargo build --target wasm32-unknown-unknown --profile release
+cargo build --target wasm32-unknown-unknown --profile release --workspace
 cp target/wasm32-unknown-unknown/release/${TARGET} artifacts/
 if [ -f artifacts/${TARGET} ];
 then
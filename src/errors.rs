@@ -0,0 +1,104 @@
+use near_sdk::env;
+
+/// Service Registry errors with stable codes.
+/// Each error panics with the `E<code>: <message>` string, e.g. `E003: Service not found`
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum RegistryError {
+    Unauthorized = 1,
+    Paused = 2,
+    ServiceNotFound = 3,
+    WrongState = 4,
+    WrongArrayLength = 5,
+    WrongAgentIds = 6,
+    WrongThreshold = 7,
+    ZeroConfigHash = 8,
+    WrongNumberOfCopies = 9,
+    AgentNotFound = 10,
+    AgentRegistryCheckFailed = 11,
+    AgentIdsNotUpdated = 12,
+    InsufficientDeposit = 13,
+    InsufficientBalance = 14,
+    TokenNotRegistered = 15,
+    AccountNotRegistered = 16,
+    InsufficientTokenBalance = 17,
+    NonZeroTokenBalance = 18,
+    WrongAgentInstance = 19,
+    DuplicateInstance = 20,
+    NoAgentInstanceSlots = 21,
+    WrongAccountId = 22,
+    MultisigCheckFailed = 23,
+    // Code 24 is retired, multisig mismatches are reported by the MultisigMembersMismatch event
+    WrongMultisig = 25,
+    DepositNotRequired = 26,
+    OperatorNotFound = 27,
+    InvalidUpgradeHash = 28,
+    UpgradeNotReady = 29,
+    MultisigFactoryNotApproved = 30,
+    DefaultMultisigFactory = 31,
+    WrongPublicKey = 32,
+    MissingInstanceConsent = 33,
+    WrongSignature = 34,
+    OperatorNotWhitelisted = 35,
+    WrongDeadline = 36,
+    RegistrationNotExpired = 37,
+    PendingTokenTransfer = 38
+}
+
+impl RegistryError {
+    pub fn code(&self) -> u32 {
+        *self as u32
+    }
+
+    pub fn message(&self) -> &'static str {
+        match self {
+            RegistryError::Unauthorized => "Unauthorized",
+            RegistryError::Paused => "Registry is paused",
+            RegistryError::ServiceNotFound => "Service not found",
+            RegistryError::WrongState => "Wrong service state",
+            RegistryError::WrongArrayLength => "Wrong array length",
+            RegistryError::WrongAgentIds => "Wrong agent ids",
+            RegistryError::WrongThreshold => "Wrong threshold",
+            RegistryError::ZeroConfigHash => "Zero config hash",
+            RegistryError::WrongNumberOfCopies => "Number of copies must be equal to one",
+            RegistryError::AgentNotFound => "Agent not found",
+            RegistryError::AgentRegistryCheckFailed => "Agent registry check failed",
+            RegistryError::AgentIdsNotUpdated => "Not all agent ids are updated",
+            RegistryError::InsufficientDeposit => "Insufficient deposit",
+            RegistryError::InsufficientBalance => "Insufficient registry balance",
+            RegistryError::TokenNotRegistered => "Token not registered",
+            RegistryError::AccountNotRegistered => "Account not registered",
+            RegistryError::InsufficientTokenBalance => "Insufficient token balance",
+            RegistryError::NonZeroTokenBalance => "Token balance must be zero",
+            RegistryError::WrongAgentInstance => "Wrong agent instance",
+            RegistryError::DuplicateInstance => "Agent instance is already registered",
+            RegistryError::NoAgentInstanceSlots => "No agent instance slots left",
+            RegistryError::WrongAccountId => "Wrong account id",
+            RegistryError::MultisigCheckFailed => "Multisig check failed",
+            RegistryError::WrongMultisig => "Wrong multisig",
+            RegistryError::DepositNotRequired => "Deposit is not required",
+            RegistryError::OperatorNotFound => "Operator not found",
+            RegistryError::InvalidUpgradeHash => "Invalid upgrade contract hash",
+            RegistryError::UpgradeNotReady => "Upgrade delay has not passed",
+            RegistryError::MultisigFactoryNotApproved => "Multisig factory is not approved",
+            RegistryError::DefaultMultisigFactory => "Default multisig factory cannot be removed",
+            RegistryError::WrongPublicKey => "Public key does not match the agent instance",
+            RegistryError::MissingInstanceConsent => "Agent instance consent is missing",
+            RegistryError::WrongSignature => "Wrong agent instance signature",
+            RegistryError::OperatorNotWhitelisted => "Operator is not whitelisted",
+            RegistryError::WrongDeadline => "Registration deadline must be in the future",
+            RegistryError::RegistrationNotExpired => "Registration deadline has not passed",
+            RegistryError::PendingTokenTransfer => "Token transfer is not settled yet"
+        }
+    }
+
+    pub fn panic(self) -> ! {
+        env::panic_str(&format!("E{:03}: {}", self.code(), self.message()))
+    }
+}
+
+/// Panics with the provided error if the condition does not hold
+pub fn ensure(condition: bool, error: RegistryError) {
+    if !condition {
+        error.panic();
+    }
+}
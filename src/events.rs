@@ -0,0 +1,285 @@
+use near_sdk::json_types::U128;
+use near_sdk::{near, AccountId, PublicKey};
+use crate::{MultisigKind, MultisigMember, Role, TokenKind};
+
+/// Service Registry events following the NEP-297 standard.
+/// Each event is logged as `EVENT_JSON:{"standard":"olas_service_registry","version":...,"event":...,"data":...}`
+#[near(event_json(standard = "olas_service_registry"))]
+pub enum RegistryEvent {
+    #[event_version("1.0.0")]
+    CreateService {
+        service_id: u32,
+        owner_id: AccountId,
+        token: Option<AccountId>,
+        // Hex encoded config hash
+        config_hash: String,
+        agent_ids: Vec<u32>,
+        agent_num_instances: Vec<u32>,
+        agent_bonds: Vec<U128>,
+        threshold: u32
+    },
+
+    #[event_version("1.0.0")]
+    UpdateService {
+        service_id: u32,
+        owner_id: AccountId,
+        token: Option<AccountId>,
+        // Hex encoded config hash
+        config_hash: String,
+        agent_ids: Vec<u32>,
+        agent_num_instances: Vec<u32>,
+        agent_bonds: Vec<U128>,
+        threshold: u32
+    },
+
+    #[event_version("1.1.0")]
+    ActivateRegistration {
+        service_id: u32,
+        owner_id: AccountId,
+        security_deposit: U128,
+        // Time in seconds after which the registration can be expired
+        registration_deadline: Option<u64>
+    },
+
+    #[event_version("1.0.0")]
+    RegistrationExpired {
+        service_id: u32,
+        owner_id: AccountId,
+        deadline: u64
+    },
+
+    #[event_version("1.1.0")]
+    RegisterInstance {
+        service_id: u32,
+        operator_id: AccountId,
+        agent_instance: AccountId,
+        agent_id: u32,
+        bond: U128,
+        public_key: Option<PublicKey>
+    },
+
+    #[event_version("1.0.0")]
+    CreateMultisigWithAgents {
+        service_id: u32,
+        multisig: AccountId
+    },
+
+    #[event_version("1.0.0")]
+    CreateMultisigFailed {
+        service_id: u32,
+        multisig: AccountId,
+        owner_id: AccountId,
+        refund: U128
+    },
+
+    #[event_version("1.0.0")]
+    UnregisterInstance {
+        service_id: u32,
+        operator_id: AccountId,
+        agent_instance: AccountId,
+        agent_id: u32,
+        bond: U128
+    },
+
+    #[event_version("1.0.0")]
+    ReplaceInstance {
+        service_id: u32,
+        operator_id: AccountId,
+        old_instance: AccountId,
+        new_instance: AccountId,
+        agent_id: u32
+    },
+
+    #[event_version("1.0.0")]
+    ReplaceInstanceFailed {
+        service_id: u32,
+        operator_id: AccountId,
+        old_instance: AccountId,
+        new_instance: AccountId
+    },
+
+    #[event_version("1.0.0")]
+    MultisigMembersMismatch {
+        service_id: u32,
+        multisig: AccountId,
+        // Agent instances that are not multisig members
+        missing: Vec<MultisigMember>,
+        // Multisig members that are not agent instances
+        extra: Vec<MultisigMember>,
+        num_confirmations: u32,
+        threshold: u32
+    },
+
+    #[event_version("1.0.0")]
+    DeployService {
+        service_id: u32,
+        multisig: AccountId
+    },
+
+    #[event_version("1.0.0")]
+    Slash {
+        service_id: u32,
+        operator_id: AccountId,
+        agent_instance: AccountId,
+        amount: U128
+    },
+
+    #[event_version("1.0.0")]
+    TerminateService {
+        service_id: u32,
+        owner_id: AccountId,
+        refund: U128
+    },
+
+    #[event_version("1.0.0")]
+    OperatorUnbond {
+        service_id: u32,
+        operator_id: AccountId,
+        refund: U128
+    },
+
+    #[event_version("1.0.0")]
+    Drain {
+        drainer_id: AccountId,
+        token: TokenKind,
+        amount: U128
+    },
+
+    #[event_version("1.0.0")]
+    TokenTransferFailed {
+        account_id: AccountId,
+        token: AccountId,
+        amount: U128
+    },
+
+    #[event_version("1.0.0")]
+    Deposit {
+        account_id: AccountId,
+        token: AccountId,
+        amount: U128
+    },
+
+    #[event_version("1.0.0")]
+    Withdraw {
+        account_id: AccountId,
+        token: AccountId,
+        amount: U128
+    },
+
+    #[event_version("1.0.0")]
+    SetOperatorsCheck {
+        service_id: u32,
+        owner_id: AccountId,
+        check: bool
+    },
+
+    #[event_version("1.0.0")]
+    OperatorsRootUpdated {
+        service_id: u32,
+        owner_id: AccountId,
+        // Hex encoded merkle root
+        root: Option<String>
+    },
+
+    #[event_version("1.0.0")]
+    OperatorsWhitelistUpdated {
+        service_id: u32,
+        owner_id: AccountId,
+        operators: Vec<AccountId>,
+        statuses: Vec<bool>,
+        check: bool
+    },
+
+    #[event_version("1.0.0")]
+    ServiceStorageMigrated {
+        service_id: u32
+    },
+
+    #[event_version("1.0.0")]
+    OwnerProposed {
+        owner_id: AccountId,
+        pending_owner_id: AccountId
+    },
+
+    #[event_version("1.0.0")]
+    OwnerProposalCancelled {
+        owner_id: AccountId,
+        pending_owner_id: AccountId
+    },
+
+    #[event_version("1.0.0")]
+    OwnerUpdated {
+        owner_id: AccountId
+    },
+
+    #[event_version("1.0.0")]
+    AgentRegistryUpdated {
+        agent_registry: Option<AccountId>
+    },
+
+    #[event_version("1.0.0")]
+    MultisigFactoryUpdated {
+        multisig_factory: AccountId
+    },
+
+    #[event_version("1.0.0")]
+    MultisigFactoryAdded {
+        multisig_factory: AccountId,
+        kind: MultisigKind
+    },
+
+    #[event_version("1.0.0")]
+    MultisigFactoryRemoved {
+        multisig_factory: AccountId
+    },
+
+    #[event_version("1.0.0")]
+    InstanceConsentRequiredUpdated {
+        required: bool
+    },
+
+    #[event_version("1.0.0")]
+    OperatorAccepted {
+        agent_instance: AccountId,
+        service_id: u32,
+        operator_id: AccountId
+    },
+
+    #[event_version("1.0.0")]
+    RoleGranted {
+        role: Role,
+        account_id: AccountId
+    },
+
+    #[event_version("1.0.0")]
+    RoleRevoked {
+        role: Role,
+        account_id: AccountId
+    },
+
+    #[event_version("1.1.0")]
+    UpgradeHashUpdated {
+        // Hex encoded code hash
+        hash: String,
+        // Time in seconds since which the upgrade is allowed
+        activation_ts: u64
+    },
+
+    #[event_version("1.0.0")]
+    UpgradeCancelled {
+        // Hex encoded code hash
+        hash: String
+    },
+
+    #[event_version("1.0.0")]
+    UpgradeDelayUpdated {
+        delay: u64,
+        // Time in seconds since which the delay takes effect
+        activation_ts: u64
+    },
+
+    #[event_version("1.0.0")]
+    PausedUpdated {
+        paused: bool
+    }
+}
@@ -5,17 +5,26 @@ use near_contract_standards::non_fungible_token::{NonFungibleToken, Token, Token
 use near_contract_standards::fungible_token::{core::ext_ft_core, receiver::FungibleTokenReceiver};
 use near_sdk::borsh::BorshSerialize;
 use near_sdk::serde::{Serialize, Deserialize};
-use near_sdk::json_types::{Base58PublicKey, U128};
+use near_sdk::json_types::{Base64VecU8, U128};
 use near_sdk::{
-    env, near, require, AccountId, BorshStorageKey, Promise, PromiseOrValue, StorageUsage, Gas, PromiseError, NearToken, log
+    env, near, AccountId, BorshStorageKey, Promise, PromiseOrValue, StorageUsage, Gas, PromiseError, NearToken, log,
+    CurveType, PublicKey
 };
-use near_sdk::store::{LookupMap, Vector};
+use near_sdk::store::{LookupMap, Vector, IterableSet, IterableMap};
 use near_sdk::ext_contract;
 
-#[derive(Serialize, Deserialize, PartialEq)]
+mod errors;
+use errors::{ensure, RegistryError};
+mod events;
+use events::RegistryEvent;
+mod migrate;
+mod sputnik;
+use sputnik::{sputnik_dao, sputnik_dao_factory};
+
+#[derive(Serialize, Deserialize, PartialEq, Clone)]
 #[serde(crate = "near_sdk::serde", untagged)]
 pub enum MultisigMember {
-    AccessKey { public_key: Base58PublicKey },
+    AccessKey { public_key: PublicKey },
     Account { account_id: AccountId },
 }
 
@@ -36,10 +45,17 @@ trait MultisigFactory {
 #[ext_contract(multisig2)]
 trait Multisig2 {
     fn get_members(&self) -> Vec<MultisigMember>;
+    fn get_num_confirmations(&self) -> u32;
+}
+
+// AgentRegistry interface
+#[ext_contract(agent_registry)]
+trait AgentRegistry {
+    fn units_exist(&self, unit_ids: Vec<u32>) -> bool;
 }
 
 
-#[near(serializers=[borsh])]
+#[near(serializers=[borsh, json])]
 #[derive(PartialEq, Clone)]
 pub enum ServiceState {
     NonExistent,
@@ -50,6 +66,42 @@ pub enum ServiceState {
     TerminatedBonded
 }
 
+// Kind of the token the service is bonded in
+#[near(serializers=[borsh, json])]
+#[derive(PartialEq, Eq, PartialOrd, Ord, Clone)]
+pub enum TokenKind {
+    Native,
+    FungibleToken(AccountId)
+}
+
+impl From<Option<AccountId>> for TokenKind {
+    fn from(token: Option<AccountId>) -> Self {
+        match token {
+            Some(token) => TokenKind::FungibleToken(token),
+            None => TokenKind::Native
+        }
+    }
+}
+
+// Interface spoken by the multisig factory and its multisigs
+#[near(serializers=[borsh, json])]
+#[derive(PartialEq, Eq, Clone, Copy)]
+pub enum MultisigKind {
+    Multisig2,
+    // Sputnik DAO v2 with agent instances as council members
+    SputnikDao
+}
+
+// Registry administration roles granted by the registry owner
+#[near(serializers=[borsh, json])]
+#[derive(PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
+pub enum Role {
+    Pauser,
+    Upgrader,
+    Drainer,
+    MultisigFactoryManager
+}
+
 #[near(serializers=[borsh])]
 pub struct AgentParams {
     pub num_agent_instances: u32,
@@ -97,12 +149,71 @@ pub struct Service {
     pub operators_check: bool
 }
 
+#[near(serializers=[json])]
+pub struct AgentParamsView {
+    pub agent_id: u32,
+    pub num_agent_instances: u32,
+    pub bond: U128,
+    pub instances: Vec<AccountId>
+}
+
+#[near(serializers=[json])]
+pub struct ServiceView {
+    pub service_id: u32,
+    pub owner_id: AccountId,
+    pub token: Option<AccountId>,
+    pub security_deposit: U128,
+    pub multisig: Option<AccountId>,
+    // Current config hash
+    pub config_hash: [u8; 32],
+    pub threshold: u32,
+    pub max_num_agent_instances: u32,
+    pub num_agent_instances: u32,
+    pub state: ServiceState,
+    pub agent_params: Vec<AgentParamsView>,
+    pub operators_check: bool
+}
+
+// Service parameters passed to create and update callbacks
+#[near(serializers=[json])]
+pub struct ServiceParams {
+    pub token: Option<AccountId>,
+    pub config_hash: [u8; 32],
+    pub agent_ids: Vec<u32>,
+    pub agent_num_instances: Vec<u32>,
+    pub agent_bonds: Vec<u128>,
+    pub threshold: u32
+}
+
+impl ServiceParams {
+    // Agent ids with non-zero agent params, i.e. the ones that are not removed from the service
+    fn active_agent_ids(&self) -> Vec<u32> {
+        (0..self.agent_ids.len())
+            .filter(|&i| self.agent_num_instances[i] > 0 && self.agent_bonds[i] > 0)
+            .map(|i| self.agent_ids[i])
+            .collect()
+    }
+}
+
 const CALL_GAS: Gas = Gas::from_tgas(5);
 const CREATE_CALL_GAS: Gas = Gas::from_tgas(100);
+const CREATE_DAO_CALL_GAS: Gas = Gas::from_tgas(150);
+const CALLBACK_GAS: Gas = Gas::from_tgas(50);
+const MIGRATE_GAS: Gas = Gas::from_tgas(50);
+// Current version of the registry state layout
+const STATE_VERSION: u32 = 8;
+const NANOSECONDS: u64 = 1_000_000_000;
+// Default and maximum numbers of services returned by the enumeration views
+const DEFAULT_PAGE_SIZE: u32 = 50;
+const MAX_PAGE_SIZE: u32 = 100;
+// Default upgrade delay of one day in seconds
+const DEFAULT_UPGRADE_DELAY: u64 = 86_400;
 
 #[near(contract_state)]
 pub struct ServiceRegistry {
     owner: AccountId,
+    // Proposed owner that is yet to accept the ownership
+    pending_owner: Option<AccountId>,
     services: LookupMap<u32, Service>,
     tokens: NonFungibleToken,
     metadata: Option<NFTContractMetadata>,
@@ -111,9 +222,68 @@ pub struct ServiceRegistry {
     paused: bool,
     multisig_factory: AccountId,
     balance: u128,
-    slashed_funds: LookupMap<AccountId, u128>,
+    slashed_funds: LookupMap<TokenKind, u128>,
     // Contract upgrade hash
-    upgrade_hash: Vec<u8>
+    upgrade_hash: Vec<u8>,
+    // Agent registry to check agent ids against, if set
+    agent_registry: Option<AccountId>,
+    // Service Ids indexed by the service state
+    services_by_state: LookupMap<u8, IterableSet<u32>>,
+    // Service Ids indexed by the service token kind
+    services_by_token: LookupMap<TokenKind, IterableSet<u32>>,
+    // Role holders by role
+    roles: LookupMap<Role, IterableSet<AccountId>>,
+    // Version of the registry state layout
+    state_version: u32,
+    // Time in seconds since which the staged upgrade hash can be used
+    upgrade_activation_ts: u64,
+    // Delay in seconds between staging the upgrade hash and the upgrade
+    upgrade_delay: u64,
+    // Decrease of the upgrade delay that is yet to take effect
+    pending_upgrade_delay: Option<UpgradeDelayChange>,
+    // Approved multisig factories with their multisig interfaces, including the default multisig factory
+    multisig_factories: IterableMap<AccountId, MultisigKind>,
+    // Public keys of agent instances that sign as multisig access keys
+    agent_instance_keys: LookupMap<AccountId, PublicKey>,
+    // Whether agent instances must consent to be registered by the operator
+    instance_consent_required: bool,
+    // Consents given by agent instances to be registered by operators
+    instance_consents: LookupMap<AccountId, InstanceConsent>,
+    // Merkle roots of allowed operators by service Id
+    operators_roots: LookupMap<u32, [u8; 32]>,
+    // Time in seconds after which the active registration of the service can be expired, by service Id
+    registration_deadlines: LookupMap<u32, u64>,
+    // Number of token transfers to the account that are not settled yet, the account token storage is kept until then
+    pending_token_transfers: LookupMap<AccountId, u32>
+}
+
+#[near(serializers=[borsh, json])]
+#[derive(PartialEq, Clone)]
+pub struct InstanceConsent {
+    pub service_id: u32,
+    pub operator_id: AccountId
+}
+
+#[near(serializers=[borsh, json])]
+#[derive(Clone)]
+pub struct UpgradeDelayChange {
+    pub delay: u64,
+    // Time in seconds since which the delay takes effect
+    pub activation_ts: u64
+}
+
+#[near(serializers=[json])]
+pub struct MultisigFactoryView {
+    pub multisig_factory: AccountId,
+    pub kind: MultisigKind,
+    pub is_default: bool
+}
+
+#[near(serializers=[json])]
+pub struct PendingUpgrade {
+    // Hex encoded code hash
+    pub hash: String,
+    pub activation_ts: u64
 }
 
 #[derive(BorshStorageKey, BorshSerialize)]
@@ -124,26 +294,144 @@ enum StorageKey {
     Enumeration,
     Approval,
     Service,
+    // Legacy prefixes shared by all the services, only kept to preserve variant indexes of already deployed state
+    #[allow(dead_code)]
     AgentId,
+    #[allow(dead_code)]
     AgentParam,
+    #[allow(dead_code)]
     AgentInstance,
+    #[allow(dead_code)]
     AgentInstancePerAgentId,
+    #[allow(dead_code)]
     ConfigHash,
+    #[allow(dead_code)]
     OperatorData,
     AgentInstanceOperator,
     CustomToken,
-    TokenBalances
+    // Legacy slashed funds prefix, only read by migrations
+    TokenBalances,
+    // Per-service namespaced prefixes
+    ServiceConfigHash { service_id: u32 },
+    ServiceAgentId { service_id: u32 },
+    ServiceAgentParam { service_id: u32 },
+    ServiceAgentInstance { service_id: u32 },
+    ServiceOperatorData { service_id: u32 },
+    ServiceAgentIdInstance { service_id: u32, agent_id: u32 },
+    ServiceOperatorInstance { service_id: u32, operator: AccountId },
+    // Per-token namespaced balances prefix
+    TokenBalancesPerToken { token: AccountId },
+    SlashedFunds,
+    // Service indexes prefixes
+    ServicesByState,
+    ServicesPerState { state: u8 },
+    ServicesByToken,
+    ServicesPerToken { token: TokenKind },
+    Roles,
+    RoleHolders { role: Role },
+    MultisigFactories,
+    AgentInstanceKeys,
+    InstanceConsents,
+    OperatorsRoots,
+    RegistrationDeadlines,
+    PendingTokenTransfers
+}
+
+// Returns the implicit account id of the ED25519 public key
+fn implicit_account_id(public_key: &PublicKey) -> Option<AccountId> {
+    if public_key.curve_type() != CurveType::ED25519 {
+        return None;
+    }
+    hex::encode(&public_key.as_bytes()[1..]).parse().ok()
+}
+
+// Returns the ED25519 public key bytes of the implicit account id
+fn implicit_public_key(account_id: &AccountId) -> Option<[u8; 32]> {
+    hex::decode(account_id.as_str()).ok()?.try_into().ok()
+}
+
+// Message signed by the agent instance to consent to be registered by the operator
+fn instance_consent_message(service_id: u32, operator: &AccountId) -> Vec<u8> {
+    format!("{}:{}:{}", env::current_account_id(), service_id, operator).into_bytes()
+}
+
+// Checks the merkle proof of the operator, where the leaf is the sha256 hash of the operator account id,
+// and each pair of nodes is hashed in the sorted order
+fn verify_operator_proof(root: &[u8; 32], operator: &AccountId, proof: &[[u8; 32]]) -> bool {
+    let mut node = env::sha256_array(operator.as_bytes());
+    for sibling in proof {
+        let (left, right) = if node <= *sibling { (node, *sibling) } else { (*sibling, node) };
+        node = env::sha256_array(&[left, right].concat());
+    }
+    node == *root
+}
+
+// Consumes the consent of the agent instance once it is registered by the operator in the service
+fn consume_instance_consent(
+    instance_consents: &mut LookupMap<AccountId, InstanceConsent>,
+    service_id: u32,
+    operator: &AccountId,
+    agent_instance: &AccountId
+) {
+    if instance_consents.get(agent_instance) == Some(&InstanceConsent { service_id, operator_id: operator.clone() }) {
+        instance_consents.remove(agent_instance);
+    }
+}
+
+fn block_timestamp_secs() -> u64 {
+    env::block_timestamp() / NANOSECONDS
+}
+
+// Moves the service Id from one index set to another
+fn reindex_service<K: BorshSerialize + Ord + Clone>(
+    index: &mut LookupMap<K, IterableSet<u32>>,
+    service_id: u32,
+    from: Option<K>,
+    to: K,
+    prefix: fn(K) -> StorageKey
+) {
+    if let Some(set) = from.and_then(|key| index.get_mut(&key)) {
+        set.remove(&service_id);
+        set.flush();
+    }
+    let set = index.entry(to.clone()).or_insert_with(|| IterableSet::new(prefix(to)));
+    set.insert(service_id);
+    set.flush();
+    index.flush();
+}
+
+fn index_service_state(
+    index: &mut LookupMap<u8, IterableSet<u32>>,
+    service_id: u32,
+    from: Option<ServiceState>,
+    to: ServiceState
+) {
+    reindex_service(index, service_id, from.map(|state| state as u8), to as u8,
+        |state| StorageKey::ServicesPerState { state });
+}
+
+fn index_service_token(
+    index: &mut LookupMap<TokenKind, IterableSet<u32>>,
+    service_id: u32,
+    from: Option<TokenKind>,
+    to: TokenKind
+) {
+    reindex_service(index, service_id, from, to, |token| StorageKey::ServicesPerToken { token });
 }
 
 #[near]
 impl ServiceRegistry {
     /// Initializes the contract
     #[init]
-    pub fn new(multisig_factory: AccountId, metadata: NFTContractMetadata) -> Self {
+    pub fn new(multisig_factory: AccountId, metadata: NFTContractMetadata, agent_registry: Option<AccountId>) -> Self {
         assert!(!env::state_exists(), "Already initialized");
         metadata.assert_valid();
+        // The default multisig factory is approved with the multisig2 interface
+        let mut multisig_factories = IterableMap::new(StorageKey::MultisigFactories);
+        multisig_factories.insert(multisig_factory.clone(), MultisigKind::Multisig2);
         Self {
             owner: env::predecessor_account_id(),
+            pending_owner: None,
             services: LookupMap::new(StorageKey::Service),
             tokens: NonFungibleToken::new(
                 StorageKey::NonFungibleToken,
@@ -158,26 +446,53 @@ impl ServiceRegistry {
             paused: false,
             multisig_factory,
             balance: 0 as u128,
-            slashed_funds: LookupMap::new(StorageKey::TokenBalances),
-            upgrade_hash: Vec::new()
+            slashed_funds: LookupMap::new(StorageKey::SlashedFunds),
+            upgrade_hash: Vec::new(),
+            agent_registry,
+            services_by_state: LookupMap::new(StorageKey::ServicesByState),
+            services_by_token: LookupMap::new(StorageKey::ServicesByToken),
+            roles: LookupMap::new(StorageKey::Roles),
+            state_version: STATE_VERSION,
+            upgrade_activation_ts: 0,
+            upgrade_delay: DEFAULT_UPGRADE_DELAY,
+            pending_upgrade_delay: None,
+            multisig_factories,
+            agent_instance_keys: LookupMap::new(StorageKey::AgentInstanceKeys),
+            instance_consent_required: false,
+            instance_consents: LookupMap::new(StorageKey::InstanceConsents),
+            operators_roots: LookupMap::new(StorageKey::OperatorsRoots),
+            registration_deadlines: LookupMap::new(StorageKey::RegistrationDeadlines),
+            pending_token_transfers: LookupMap::new(StorageKey::PendingTokenTransfers)
         }
     }
 
     fn refund_deposit_to_account(&self, storage_used: u64, service_deposit: u128, account_id: AccountId, deposit_in: bool) {
+        self.refund_deposit_from(storage_used, service_deposit, account_id, deposit_in, env::attached_deposit());
+    }
+
+    // Same as refund_deposit_to_account, but with the deposit provided explicitly, as required in callbacks
+    fn refund_deposit_from(
+        &self,
+        storage_used: u64,
+        service_deposit: u128,
+        account_id: AccountId,
+        deposit_in: bool,
+        attached_deposit: NearToken
+    ) {
         log!("storage used: {}", storage_used);
         let near_deposit = NearToken::from_yoctonear(service_deposit);
         let mut required_cost = env::storage_byte_cost().saturating_mul(storage_used.into());
         required_cost = required_cost.saturating_add(near_deposit);
 
-        let mut refund = env::attached_deposit();
+        let mut refund = attached_deposit;
         // Deposit is added on a balance
         if deposit_in {
             // Required cost must not be bigger than the attached deposit
-            require!(required_cost <= refund);
+            ensure(required_cost <= refund, RegistryError::InsufficientDeposit);
             refund = refund.saturating_sub(required_cost);
         } else {
             // This could be the case if the storage price went up during the lifespan of the service
-            require!(required_cost <= env::account_balance());
+            ensure(required_cost <= env::account_balance(), RegistryError::InsufficientBalance);
             refund = refund.saturating_add(required_cost);
         }
         //log!("required cost: {}", required_cost.as_yoctonear());
@@ -188,16 +503,75 @@ impl ServiceRegistry {
         }
     }
 
-    pub fn change_owner(&mut self, new_owner: AccountId) {
+    /// Panics if the registry is paused.
+    /// Exit paths (terminate, expire_registration, unbond, unregister_agents, withdraw, storage_withdraw) are not gated,
+    /// such that funds are always recoverable
+    fn require_not_paused(&self) {
+        ensure(!self.paused, RegistryError::Paused);
+    }
+
+    /// Proposes the new owner, that has to accept the ownership to become the owner
+    pub fn propose_owner(&mut self, new_owner: AccountId) {
         // Check the ownership
-        require!(self.owner == env::predecessor_account_id());
+        ensure(self.owner == env::predecessor_account_id(), RegistryError::Unauthorized);
 
         // Check account validity
-        require!(env::is_valid_account_id(new_owner.as_bytes()));
+        ensure(env::is_valid_account_id(new_owner.as_bytes()), RegistryError::WrongAccountId);
 
-        self.owner = new_owner;
+        self.pending_owner = Some(new_owner.clone());
 
-        // TODO: event
+        RegistryEvent::OwnerProposed { owner_id: self.owner.clone(), pending_owner_id: new_owner }.emit();
+    }
+
+    // Call by the pending owner
+    pub fn accept_ownership(&mut self) {
+        // Check for the pending owner
+        let new_owner = env::predecessor_account_id();
+        ensure(self.pending_owner.as_ref() == Some(&new_owner), RegistryError::Unauthorized);
+
+        self.owner = new_owner.clone();
+        self.pending_owner = None;
+
+        RegistryEvent::OwnerUpdated { owner_id: new_owner }.emit();
+    }
+
+    pub fn cancel_owner_proposal(&mut self) {
+        // Check the ownership
+        ensure(self.owner == env::predecessor_account_id(), RegistryError::Unauthorized);
+
+        if let Some(pending_owner_id) = self.pending_owner.take() {
+            RegistryEvent::OwnerProposalCancelled { owner_id: self.owner.clone(), pending_owner_id }.emit();
+        }
+    }
+
+    /// Panics if the caller is neither the owner, nor self, nor the holder of the role
+    fn require_role(&self, role: Role) {
+        let caller = env::predecessor_account_id();
+        ensure(self.owner_or_self() || self.has_role(role, caller), RegistryError::Unauthorized);
+    }
+
+    pub fn grant_role(&mut self, role: Role, account_id: AccountId) {
+        // Check the ownership
+        ensure(self.owner == env::predecessor_account_id(), RegistryError::Unauthorized);
+
+        let holders = self.roles.entry(role).or_insert_with(|| IterableSet::new(StorageKey::RoleHolders { role }));
+        if holders.insert(account_id.clone()) {
+            holders.flush();
+            self.roles.flush();
+            RegistryEvent::RoleGranted { role, account_id }.emit();
+        }
+    }
+
+    pub fn revoke_role(&mut self, role: Role, account_id: AccountId) {
+        // Check the ownership
+        ensure(self.owner == env::predecessor_account_id(), RegistryError::Unauthorized);
+
+        if let Some(holders) = self.roles.get_mut(&role) {
+            if holders.remove(&account_id) {
+                holders.flush();
+                RegistryEvent::RoleRevoked { role, account_id }.emit();
+            }
+        }
     }
 
     fn check_service_params(
@@ -205,25 +579,37 @@ impl ServiceRegistry {
         config_hash: [u8; 32],
         agent_ids: Vec<u32>,
         agent_num_instances: Vec<u32>,
-        agent_bonds: Vec<u128>
+        agent_bonds: Vec<u128>,
+        threshold: u32
     ) {
         // Check array lengths
-        require!(agent_ids.len() > 0);
-        require!(agent_ids.len() == agent_bonds.len());
-        require!(agent_ids.len() == agent_num_instances.len());
+        ensure(!agent_ids.is_empty(), RegistryError::WrongAgentIds);
+        ensure(agent_ids.len() == agent_bonds.len(), RegistryError::WrongArrayLength);
+        ensure(agent_ids.len() == agent_num_instances.len(), RegistryError::WrongArrayLength);
+
+        // Get the maximum number of agent instances, ignoring zero agent params
+        let max_num_agent_instances: u32 = (0..agent_ids.len())
+            .filter(|&i| agent_num_instances[i] > 0 && agent_bonds[i] > 0)
+            .map(|i| agent_num_instances[i])
+            .sum();
+
+        // Check for the correct threshold: no less than ceil((n * 2 + 1) / 3) of all the agent instances combined
+        let mut check_threshold = max_num_agent_instances * 2 + 1;
+        check_threshold = check_threshold.div_ceil(3);
+        ensure(threshold >= check_threshold && threshold <= max_num_agent_instances, RegistryError::WrongThreshold);
 
         // Check config hash
-        require!(!config_hash.into_iter().all(|h| h == 0));
+        ensure(!config_hash.into_iter().all(|h| h == 0), RegistryError::ZeroConfigHash);
 
         // Check uniqueness of agent ids: sorted agent ids must match its size with the original array
         let mut check_agent_ids = agent_ids.clone();
         check_agent_ids.sort_unstable();
         check_agent_ids.dedup();
-        require!(check_agent_ids.len() == agent_ids.len());
+        ensure(check_agent_ids.len() == agent_ids.len(), RegistryError::WrongAgentIds);
         //let v: Vec<_> = agent_ids.into_iter().unique().collect();
 
         // Check non-zero agent Ids
-        require!(agent_ids.into_iter().all(|id| id > 0));
+        ensure(agent_ids.into_iter().all(|id| id > 0), RegistryError::WrongAgentIds);
     }
 
     fn fill_service_params(
@@ -238,10 +624,10 @@ impl ServiceRegistry {
         threshold: u32
     ) {
         // Get the service
-        let service = self.services.get_mut(&service_id).unwrap();
+        let service = self.services.get_mut(&service_id).unwrap_or_else(|| RegistryError::ServiceNotFound.panic());
 
         // Check the service state
-        require!(service.state == ServiceState::PreRegistration);
+        ensure(service.state == ServiceState::PreRegistration, RegistryError::WrongState);
 
         let mut security_deposit = 0;
         let mut max_num_agent_instances = 0;
@@ -249,7 +635,7 @@ impl ServiceRegistry {
         // Process agent ids and corresponding agent params
         for i in 0..agent_ids.len() {
             let agent_id = agent_ids[i];
-            require!(agent_id > 0);
+            ensure(agent_id > 0, RegistryError::WrongAgentIds);
 
             // Ignore zero agent params, as it is the case for the service update
             if agent_num_instances[i] > 0 && agent_bonds[i] > 0 {
@@ -260,7 +646,7 @@ impl ServiceRegistry {
                     AgentParams{
                         num_agent_instances: agent_num_instances[i],
                         bond: agent_bonds[i],
-                        instances: Vector::new(StorageKey::AgentInstancePerAgentId)
+                        instances: Vector::new(StorageKey::ServiceAgentIdInstance { service_id, agent_id })
                     }
                 );
 
@@ -286,12 +672,13 @@ impl ServiceRegistry {
         // Check the token field and register the service owner, if required
         if token.is_some() && token != service.token {
             // Initialize or get registered token map
+            let token_id = token.clone().unwrap();
             let token_balances = self
                 .all_token_balances
                 // Get token map
-                .entry(token.clone().unwrap())
+                .entry(token_id.clone())
                 // or create a new one if not
-                .or_insert(LookupMap::new(StorageKey::TokenBalances));
+                .or_insert(LookupMap::new(StorageKey::TokenBalancesPerToken { token: token_id }));
 
             // Check if the service owner is registered
             if !token_balances.contains_key(&service_owner) {
@@ -299,24 +686,18 @@ impl ServiceRegistry {
                 token_balances.flush();
             }
             self.all_token_balances.flush();
+            index_service_token(&mut self.services_by_token, service_id, Some(TokenKind::from(service.token.clone())),
+                TokenKind::from(token.clone()));
             service.token = token.clone();
         }
 
         // Manage slashed funds map
-        let mut slashed_token = "near.near".parse().unwrap();
-        if token.is_some() {
-            slashed_token = token.unwrap();
-        }
+        let slashed_token = TokenKind::from(token);
         if !self.slashed_funds.contains_key(&slashed_token) {
             self.slashed_funds.set(slashed_token, Some(0));
             self.slashed_funds.flush();
         }
 
-        // Check for the correct threshold: no less than ceil((n * 2 + 1) / 3) of all the agent instances combined
-        let mut check_threshold = max_num_agent_instances * 2 + 1;
-        check_threshold = check_threshold.div_ceil(3);
-        require!(threshold >= check_threshold && threshold <= max_num_agent_instances);
-
         service.threshold = threshold;
 
         // Record the first config hash if the service is created, or update it, if necessary
@@ -338,32 +719,17 @@ impl ServiceRegistry {
         }
     }
 
-    #[payable]
-    pub fn create(
+    fn internal_create(
         &mut self,
         service_owner: AccountId,
         metadata: TokenMetadata,
-        token: Option<AccountId>,
-        config_hash: [u8; 32],
-        agent_ids: Vec<u32>,
-        agent_num_instances: Vec<u32>,
-        agent_bonds: Vec<u128>,
-        threshold: u32
+        params: ServiceParams,
+        depositor: AccountId,
+        deposit: NearToken
     ) -> bool {
         // Record current storage usage
         let initial_storage_usage = env::storage_usage();
 
-        // TODO Check other fields?
-        // Number of copies must be equal to one
-        require!(metadata.copies.unwrap() == 1);
-
-        self.check_service_params(
-            config_hash,
-            agent_ids.clone(),
-            agent_num_instances.clone(),
-            agent_bonds.clone()
-        );
-
         // Get the current total supply
         let supply = self.tokens.owner_by_id.len() as u32;
         // To be consistent with EVM where Ids start from 1, each new token Id is equal to supply + 1
@@ -381,56 +747,61 @@ impl ServiceRegistry {
                 token: None,
                 security_deposit: 0,
                 multisig: None,
-                config_hashes: Vector::new(StorageKey::ConfigHash),
+                config_hashes: Vector::new(StorageKey::ServiceConfigHash { service_id }),
                 threshold: 0,
                 max_num_agent_instances: 0,
                 num_agent_instances: 0,
                 state: ServiceState::PreRegistration,
-                agent_ids: Vector::new(StorageKey::AgentId),
-                agent_params: LookupMap::new(StorageKey::AgentParam),
-                agent_instances: LookupMap::new(StorageKey::AgentInstance),
-                operators: LookupMap::new(StorageKey::OperatorData),
+                agent_ids: Vector::new(StorageKey::ServiceAgentId { service_id }),
+                agent_params: LookupMap::new(StorageKey::ServiceAgentParam { service_id }),
+                agent_instances: LookupMap::new(StorageKey::ServiceAgentInstance { service_id }),
+                operators: LookupMap::new(StorageKey::ServiceOperatorData { service_id }),
                 operators_check: false
             }
         );
+        index_service_state(&mut self.services_by_state, service_id, None, ServiceState::PreRegistration);
+        index_service_token(&mut self.services_by_token, service_id, None, TokenKind::Native);
 
         // Fill in the service parameters
         self.fill_service_params(
-            service_owner,
+            service_owner.clone(),
             service_id,
-            token,
-            config_hash,
-            agent_ids.clone(),
-            agent_num_instances.clone(),
-            agent_bonds.clone(),
-            threshold
+            params.token.clone(),
+            params.config_hash,
+            params.agent_ids.clone(),
+            params.agent_num_instances.clone(),
+            params.agent_bonds.clone(),
+            params.threshold
         );
         // Record service map state
         self.services.flush();
 
         // Increased storage
-//         log!("initial storage usage {}", initial_storage_usage);
-//         log!("storage usage after {}", env::storage_usage());
         let storage = env::storage_usage() - initial_storage_usage;
-        self.refund_deposit_to_account(storage, 0, env::predecessor_account_id(), true);
+        self.refund_deposit_from(storage, 0, depositor, true, deposit);
 
-        // TODO: event
+        RegistryEvent::CreateService {
+            service_id,
+            owner_id: service_owner,
+            token: params.token,
+            config_hash: hex::encode(params.config_hash),
+            agent_ids: params.agent_ids,
+            agent_num_instances: params.agent_num_instances,
+            agent_bonds: params.agent_bonds.into_iter().map(U128::from).collect(),
+            threshold: params.threshold
+        }.emit();
 
         // TODO: If this return if needed, propagate to other functions
         true
     }
 
-    #[payable]
-    pub fn update(
+    fn internal_update(
         &mut self,
         service_id: u32,
-        token: Option<AccountId>,
-        config_hash: [u8; 32],
-        agent_ids: Vec<u32>,
-        agent_num_instances: Vec<u32>,
-        agent_bonds: Vec<u128>,
-        threshold: u32
-    ) {
+        params: ServiceParams,
+        depositor: AccountId,
+        deposit: NearToken
+    ) -> bool {
         // Record current storage usage
         let initial_storage_usage = env::storage_usage();
 
@@ -438,25 +809,134 @@ impl ServiceRegistry {
         let owner_id = self.tokens
             .owner_by_id
             .get(&service_id.to_string())
-            .unwrap_or_else(|| env::panic_str("Service not found"));
-        require!(env::predecessor_account_id() == owner_id, "Predecessor must be token owner.");
+            .unwrap_or_else(|| RegistryError::ServiceNotFound.panic());
+        ensure(depositor == owner_id, RegistryError::Unauthorized);
 
         // Check that all current agent ids are updated / removed to correspond the CRUD way
-        let service = self.services.get(&service_id).unwrap();
-        require!(service.agent_ids.iter().all(|ai| agent_ids.contains(ai)), "Not all agent Ids are updated");
+        let service = self.services.get(&service_id).unwrap_or_else(|| RegistryError::ServiceNotFound.panic());
+        ensure(service.agent_ids.iter().all(|ai| params.agent_ids.contains(ai)), RegistryError::AgentIdsNotUpdated);
+
+        // Fill in the service parameters
+        self.fill_service_params(
+            owner_id.clone(),
+            service_id,
+            params.token.clone(),
+            params.config_hash,
+            params.agent_ids.clone(),
+            params.agent_num_instances.clone(),
+            params.agent_bonds.clone(),
+            params.threshold
+        );
+
+        // Increased storage
+        let storage = env::storage_usage() - initial_storage_usage;
+        self.refund_deposit_from(storage, 0, depositor, true, deposit);
+
+        RegistryEvent::UpdateService {
+            service_id,
+            owner_id,
+            token: params.token,
+            config_hash: hex::encode(params.config_hash),
+            agent_ids: params.agent_ids,
+            agent_num_instances: params.agent_num_instances,
+            agent_bonds: params.agent_bonds.into_iter().map(U128::from).collect(),
+            threshold: params.threshold
+        }.emit();
+
+        true
+    }
+
+    // Checks that all the active service agent ids exist in the agent registry
+    fn check_agent_ids(&self, agent_registry: AccountId, params: &ServiceParams) -> Promise {
+        agent_registry::ext(agent_registry)
+            .with_static_gas(CALL_GAS)
+            .units_exist(params.active_agent_ids())
+    }
+
+    #[payable]
+    pub fn create(
+        &mut self,
+        service_owner: AccountId,
+        metadata: TokenMetadata,
+        token: Option<AccountId>,
+        config_hash: [u8; 32],
+        agent_ids: Vec<u32>,
+        agent_num_instances: Vec<u32>,
+        agent_bonds: Vec<u128>,
+        threshold: u32
+    ) -> PromiseOrValue<bool> {
+        // Check for the paused state
+        self.require_not_paused();
+
+        // TODO Check other fields?
+        // Number of copies must be equal to one
+        ensure(metadata.copies == Some(1), RegistryError::WrongNumberOfCopies);
 
         self.check_service_params(
             config_hash,
             agent_ids.clone(),
             agent_num_instances.clone(),
-            agent_bonds.clone()
+            agent_bonds.clone(),
+            threshold
         );
 
-        // Fill in the service parameters
-        self.fill_service_params(
-            owner_id,
-            service_id,
-            token,
+        let params = ServiceParams { token, config_hash, agent_ids, agent_num_instances, agent_bonds, threshold };
+        let depositor = env::predecessor_account_id();
+
+        if let Some(agent_registry) = self.agent_registry.clone() {
+            // Create the service only if all its agent ids exist in the agent registry
+            let deposit = U128::from(env::attached_deposit().as_yoctonear());
+            PromiseOrValue::Promise(self.check_agent_ids(agent_registry, &params)
+                .then(
+                    Self::ext(env::current_account_id())
+                        .with_static_gas(CALLBACK_GAS)
+                        .create_callback(service_owner, metadata, params, depositor.clone(), deposit)
+                )
+                .then(
+                    Self::ext(env::current_account_id())
+                        .with_static_gas(CALL_GAS)
+                        .resolve_deposit(depositor, deposit)
+                ))
+        } else {
+            PromiseOrValue::Value(self.internal_create(service_owner, metadata, params, depositor, env::attached_deposit()))
+        }
+    }
+
+    #[private]
+    pub fn create_callback(
+        &mut self,
+        service_owner: AccountId,
+        metadata: TokenMetadata,
+        params: ServiceParams,
+        depositor: AccountId,
+        deposit: U128,
+        #[callback_result] call_result: Result<bool, PromiseError>,
+    ) -> bool {
+        // Check that all the agent ids exist
+        let exist = call_result.unwrap_or_else(|_| RegistryError::AgentRegistryCheckFailed.panic());
+        ensure(exist, RegistryError::AgentNotFound);
+
+        // The registry could have been paused in the meantime
+        self.require_not_paused();
+
+        self.internal_create(service_owner, metadata, params, depositor, NearToken::from_yoctonear(deposit.0))
+    }
+
+    #[payable]
+    pub fn update(
+        &mut self,
+        service_id: u32,
+        token: Option<AccountId>,
+        config_hash: [u8; 32],
+        agent_ids: Vec<u32>,
+        agent_num_instances: Vec<u32>,
+        agent_bonds: Vec<u128>,
+        threshold: u32
+    ) -> PromiseOrValue<bool> {
+        // Check for the paused state
+        self.require_not_paused();
+
+        self.check_service_params(
             config_hash,
             agent_ids.clone(),
             agent_num_instances.clone(),
@@ -464,118 +944,256 @@ impl ServiceRegistry {
             threshold
         );
 
-        // Increased storage
-        let storage = env::storage_usage() - initial_storage_usage;
-        self.refund_deposit_to_account(storage, 0, env::predecessor_account_id(), true);
+        let params = ServiceParams { token, config_hash, agent_ids, agent_num_instances, agent_bonds, threshold };
+        let depositor = env::predecessor_account_id();
+
+        if let Some(agent_registry) = self.agent_registry.clone() {
+            // Update the service only if all its agent ids exist in the agent registry
+            let deposit = U128::from(env::attached_deposit().as_yoctonear());
+            PromiseOrValue::Promise(self.check_agent_ids(agent_registry, &params)
+                .then(
+                    Self::ext(env::current_account_id())
+                        .with_static_gas(CALLBACK_GAS)
+                        .update_callback(service_id, params, depositor.clone(), deposit)
+                )
+                .then(
+                    Self::ext(env::current_account_id())
+                        .with_static_gas(CALL_GAS)
+                        .resolve_deposit(depositor, deposit)
+                ))
+        } else {
+            PromiseOrValue::Value(self.internal_update(service_id, params, depositor, env::attached_deposit()))
+        }
+    }
+
+    #[private]
+    pub fn update_callback(
+        &mut self,
+        service_id: u32,
+        params: ServiceParams,
+        depositor: AccountId,
+        deposit: U128,
+        #[callback_result] call_result: Result<bool, PromiseError>,
+    ) -> bool {
+        // Check that all the agent ids exist
+        let exist = call_result.unwrap_or_else(|_| RegistryError::AgentRegistryCheckFailed.panic());
+        ensure(exist, RegistryError::AgentNotFound);
+
+        // The registry could have been paused in the meantime
+        self.require_not_paused();
+
+        self.internal_update(service_id, params, depositor, NearToken::from_yoctonear(deposit.0))
+    }
 
-        // TODO: event
+    #[private]
+    pub fn resolve_deposit(
+        &self,
+        depositor: AccountId,
+        deposit: U128,
+        #[callback_result] call_result: Result<bool, PromiseError>,
+    ) -> bool {
+        match call_result {
+            Ok(success) => success,
+            Err(_) => {
+                // Refund the full deposit if the service creation or update has failed
+                if deposit.0 > 0 {
+                    Promise::new(depositor).transfer(NearToken::from_yoctonear(deposit.0));
+                }
+                false
+            }
+        }
+    }
+
+    pub fn change_agent_registry(&mut self, agent_registry: Option<AccountId>) {
+        // Check the ownership
+        ensure(self.owner == env::predecessor_account_id(), RegistryError::Unauthorized);
+
+        self.agent_registry = agent_registry.clone();
+
+        RegistryEvent::AgentRegistryUpdated { agent_registry }.emit();
+    }
+
+    /// Changes the default multisig factory, which must be approved
+    pub fn change_multisig_factory(&mut self, multisig_factory: AccountId) {
+        self.require_role(Role::MultisigFactoryManager);
+        ensure(self.multisig_factories.contains_key(&multisig_factory), RegistryError::MultisigFactoryNotApproved);
+
+        self.multisig_factory = multisig_factory.clone();
+
+        RegistryEvent::MultisigFactoryUpdated { multisig_factory }.emit();
+    }
+
+    /// Approves the multisig factory with the multisig interface it speaks, or updates its interface
+    pub fn add_multisig_factory(&mut self, multisig_factory: AccountId, kind: MultisigKind) {
+        self.require_role(Role::MultisigFactoryManager);
+
+        self.multisig_factories.insert(multisig_factory.clone(), kind);
+
+        RegistryEvent::MultisigFactoryAdded { multisig_factory, kind }.emit();
+    }
+
+    pub fn remove_multisig_factory(&mut self, multisig_factory: AccountId) {
+        self.require_role(Role::MultisigFactoryManager);
+        // The default multisig factory must be changed first
+        ensure(multisig_factory != self.multisig_factory, RegistryError::DefaultMultisigFactory);
+        ensure(self.multisig_factories.remove(&multisig_factory).is_some(), RegistryError::MultisigFactoryNotApproved);
+
+        RegistryEvent::MultisigFactoryRemoved { multisig_factory }.emit();
     }
 
     #[payable]
+    /// Activates the service agent registration, optionally with the registration deadline in seconds,
+    /// after which the registration can be expired by anyone if not finished
     pub fn activate_registration(
         &mut self,
         service_id: u32,
-        account_id: Option<AccountId>
+        account_id: Option<AccountId>,
+        registration_deadline: Option<u64>
     ) {
+        // Check for the paused state
+        self.require_not_paused();
+
         let service_owner = account_id.unwrap_or_else(env::predecessor_account_id);
 
         // Check for service owner
         let owner_id = self.tokens
             .owner_by_id
             .get(&service_id.to_string())
-            .unwrap_or_else(|| env::panic_str("Service not found"));
-        require!(service_owner == owner_id, "Predecessor must be token owner.");
+            .unwrap_or_else(|| RegistryError::ServiceNotFound.panic());
+        ensure(service_owner == owner_id, RegistryError::Unauthorized);
 
         // Get the service
-        let service = self.services.get_mut(&service_id).unwrap();
+        let service = self.services.get_mut(&service_id).unwrap_or_else(|| RegistryError::ServiceNotFound.panic());
 
         // Check the service state
-        require!(service.state == ServiceState::PreRegistration);
+        ensure(service.state == ServiceState::PreRegistration, RegistryError::WrongState);
+
+        // Record current storage usage
+        let initial_storage_usage = env::storage_usage();
+
+        // Record the registration deadline, replacing the one of the previous registration
+        match registration_deadline {
+            Some(deadline) => {
+                ensure(deadline > block_timestamp_secs(), RegistryError::WrongDeadline);
+                self.registration_deadlines.insert(service_id, deadline)
+            }
+            None => self.registration_deadlines.remove(&service_id)
+        };
+        self.registration_deadlines.flush();
 
         // Update service state
+        index_service_state(&mut self.services_by_state, service_id, Some(service.state.clone()), ServiceState::ActiveRegistration);
         service.state = ServiceState::ActiveRegistration;
 
         let security_deposit = service.security_deposit;
 
         // Increased storage
 //         log!("storage usage after {}", env::storage_usage());
+        let storage = env::storage_usage().saturating_sub(initial_storage_usage);
 
         if service.token.is_none() {
             // Update registry native token balance
             self.balance = self.balance.saturating_add(security_deposit.into());
-            self.refund_deposit_to_account(0, security_deposit, env::predecessor_account_id(), true);
+            // Consume storage and security deposit cost and refund the rest
+            self.refund_deposit_to_account(storage, security_deposit, env::predecessor_account_id(), true);
         } else {
             // Get token balance for the service owner and reduce it by a security deposit value
             if let Some(b) = self
                 .all_token_balances
                 .get_mut(&service.token.clone().unwrap())
-                .unwrap_or_else(|| env::panic_str("Token not registered"))
+                .unwrap_or_else(|| RegistryError::TokenNotRegistered.panic())
                 .get_mut(&owner_id)
             {
                 // Decrease by the security deposit amount
                 if *b < security_deposit {
-                    env::panic_str("Not enough token deposit");
+                    RegistryError::InsufficientTokenBalance.panic();
                 }
                 *b -= security_deposit;
             } else {
                 // Fail otherwise
-                env::panic_str("Sender not registered");
+                RegistryError::AccountNotRegistered.panic();
             }
+
+            // Consume storage cost and refund the rest
+            self.refund_deposit_to_account(storage, 0, env::predecessor_account_id(), true);
         }
 
-        // TODO: event
+        RegistryEvent::ActivateRegistration {
+            service_id,
+            owner_id,
+            security_deposit: U128::from(security_deposit),
+            registration_deadline
+        }.emit();
     }
 
     #[payable]
+    /// Registers agent instances, optionally with their public keys to become multisig access key members.
+    /// An agent instance that only has a public key is registered with its implicit account id.
+    /// If the instance consent is required, each implicit account agent instance provides the ED25519 signature of
+    /// `<registry>:<service_id>:<operator>`, and any other agent instance calls accept_operator() beforehand.
+    /// If the operators check is applied, the operator is whitelisted or provides the merkle proof of the operators root.
     pub fn register_agents(
         &mut self,
         service_id: u32,
         agent_instances: Vec<AccountId>,
-        agent_ids: Vec<u32>
+        agent_ids: Vec<u32>,
+        public_keys: Option<Vec<Option<PublicKey>>>,
+        signatures: Option<Vec<Option<Base64VecU8>>>,
+        operator_proof: Option<Vec<[u8; 32]>>
     ) {
+        // Check for the paused state
+        self.require_not_paused();
+
         // Check array lengths
-        require!(agent_ids.len() == agent_instances.len());
+        ensure(agent_ids.len() == agent_instances.len(), RegistryError::WrongArrayLength);
+        let public_keys = public_keys.unwrap_or_else(|| vec![None; agent_instances.len()]);
+        ensure(public_keys.len() == agent_instances.len(), RegistryError::WrongArrayLength);
+        let signatures = signatures.unwrap_or_else(|| vec![None; agent_instances.len()]);
+        ensure(signatures.len() == agent_instances.len(), RegistryError::WrongArrayLength);
 
         let operator = env::predecessor_account_id();
 
         // Record current storage usage
         let initial_storage_usage = env::storage_usage();
 
+        // Check the operator and agent instances to be registered
+        self.check_new_instances(service_id, &operator, &agent_instances, &public_keys, &signatures, operator_proof);
+
         // Get the service
         // TODO Check if service id exists?
-        let service = self.services.get_mut(&service_id).unwrap();
+        let service = self.services.get_mut(&service_id).unwrap_or_else(|| RegistryError::ServiceNotFound.panic());
 
         // Check the service state
-        require!(service.state == ServiceState::ActiveRegistration);
+        ensure(service.state == ServiceState::ActiveRegistration, RegistryError::WrongState);
 
         // Initialize or get operator struct
         let operator_data = service
             .operators
             // Get operator struct
             .entry(operator.clone())
-            // or create a new one if not
+            // or create a new one if not, the operator is only whitelisted by the service owner
             .or_insert(OperatorData{
                 balance: 0 as u128,
-                instances: Vector::new(StorageKey::AgentInstance),
-                whitelisted: true
+                instances: Vector::new(StorageKey::ServiceOperatorInstance { service_id, operator: operator.clone() }),
+                whitelisted: false
             });
 
         // Traverse agent instances and corresponding agent ids
         let mut total_bond = 0 as u128;
         for i in 0..agent_ids.len() {
-            // Operator address must be different from agent instance one
-            require!(operator != agent_instances[i]);
-
-            // Check account validity
-            require!(env::is_valid_account_id(agent_instances[i].as_bytes()));
-
             // Check if there is an empty slot for the agent instance in this specific service
-            let agent_params = service.agent_params.get_mut(&agent_ids[i]).unwrap();
-            require!(agent_params.num_agent_instances > agent_params.instances.len() as u32);
+            let agent_params = service.agent_params.get_mut(&agent_ids[i]).unwrap_or_else(|| RegistryError::AgentNotFound.panic());
+            ensure(agent_params.num_agent_instances > agent_params.instances.len() as u32, RegistryError::NoAgentInstanceSlots);
 
             // Check that the agent instance address is unique across all services
             let res = self.agent_instance_operators.insert(agent_instances[i].clone(), operator.clone());
-            require!(res.is_none());
+            ensure(res.is_none(), RegistryError::DuplicateInstance);
+
+            // Record the agent instance public key
+            if let Some(public_key) = &public_keys[i] {
+                self.agent_instance_keys.insert(agent_instances[i].clone(), public_key.clone());
+            }
+            consume_instance_consent(&mut self.instance_consents, service_id, &operator, &agent_instances[i]);
 
             // Add agent instance into corresponding maps
             agent_params.instances.push(agent_instances[i].clone());
@@ -589,10 +1207,20 @@ impl ServiceRegistry {
 
             // Increase the total bond
             total_bond = total_bond.saturating_add(agent_params.bond.into());
+
+            RegistryEvent::RegisterInstance {
+                service_id,
+                operator_id: operator.clone(),
+                agent_instance: agent_instances[i].clone(),
+                agent_id: agent_ids[i],
+                bond: U128::from(agent_params.bond),
+                public_key: public_keys[i].clone()
+            }.emit();
         }
 
         // If the service agent instance capacity is reached, the service registration is finished
         if service.num_agent_instances == service.max_num_agent_instances {
+            index_service_state(&mut self.services_by_state, service_id, Some(service.state.clone()), ServiceState::FinishedRegistration);
             service.state = ServiceState::FinishedRegistration;
         }
 
@@ -603,6 +1231,7 @@ impl ServiceRegistry {
         service.operators.flush();
         service.agent_instances.flush();
         self.agent_instance_operators.flush();
+        self.agent_instance_keys.flush();
 
         // Increased storage
 //         log!("initial storage usage {}", initial_storage_usage);
@@ -614,17 +1243,17 @@ impl ServiceRegistry {
             if let Some(b) = self
                 .all_token_balances
                 .get_mut(&service.token.clone().unwrap())
-                .unwrap_or_else(|| env::panic_str("Token not registered"))
+                .unwrap_or_else(|| RegistryError::TokenNotRegistered.panic())
                 .get_mut(&operator)
             {
                 // Decrease by the security deposit amount
                 if *b < total_bond {
-                    env::panic_str("Not enough token deposit");
+                    RegistryError::InsufficientTokenBalance.panic();
                 }
                 *b -= total_bond;
             } else {
                 // Fail otherwise
-                env::panic_str("Sender not registered");
+                RegistryError::AccountNotRegistered.panic();
             }
 
             // Security deposit is set to zero since it was deposited via token transfer already
@@ -636,8 +1265,6 @@ impl ServiceRegistry {
 
         // Consume storage and bond cost and refund the rest
         self.refund_deposit_to_account(storage, total_bond, env::predecessor_account_id(), true);
-
-        // TODO: event
     }
 
     pub fn get_multisig_members(&self, name_multisig: AccountId) -> Promise {
@@ -662,34 +1289,145 @@ impl ServiceRegistry {
     ) -> u64 {
         // Check if the promise succeeded by calling the method outlined in external.rs
         if call_result.is_err() {
-            env::panic_str("Multisig check failed");
+            RegistryError::MultisigCheckFailed.panic();
         }
 
         call_result.unwrap().len() as u64
     }
 
+    // Checks that the operator is allowed to register agent instances in the service, either being whitelisted
+    // or with the merkle proof, and that each agent instance is a valid account different from the operator,
+    // has given its consent if required and, if an implicit account, matches its public key
+    fn check_new_instances(
+        &self,
+        service_id: u32,
+        operator: &AccountId,
+        agent_instances: &[AccountId],
+        public_keys: &[Option<PublicKey>],
+        signatures: &[Option<Base64VecU8>],
+        operator_proof: Option<Vec<[u8; 32]>>
+    ) {
+        // Check the operator whitelisting status, if applied by the service owner
+        let is_whitelisted = self.is_operator_whitelisted(service_id, operator.clone())
+            || operator_proof.is_some_and(|proof| self.is_operator_allowed(service_id, operator.clone(), proof));
+        ensure(is_whitelisted, RegistryError::OperatorNotWhitelisted);
+
+        for i in 0..agent_instances.len() {
+            // Operator address must be different from agent instance one
+            ensure(*operator != agent_instances[i], RegistryError::WrongAgentInstance);
+
+            // Check account validity
+            ensure(env::is_valid_account_id(agent_instances[i].as_bytes()), RegistryError::WrongAgentInstance);
+
+            // Check the agent instance consent to be registered by the operator
+            if self.instance_consent_required {
+                self.check_instance_consent(service_id, operator, &agent_instances[i], &signatures[i]);
+            }
+
+            // The implicit account agent instance must be the account of its key
+            if let Some(public_key) = &public_keys[i] {
+                let is_implicit = agent_instances[i].as_str().len() == 64
+                    && agent_instances[i].as_str().chars().all(|c| c.is_ascii_hexdigit());
+                ensure(!is_implicit || implicit_account_id(public_key).as_ref() == Some(&agent_instances[i]),
+                    RegistryError::WrongPublicKey);
+            }
+        }
+    }
+
+    // Checks the agent instance signature, or the consent given by the agent instance
+    fn check_instance_consent(
+        &self,
+        service_id: u32,
+        operator: &AccountId,
+        agent_instance: &AccountId,
+        signature: &Option<Base64VecU8>
+    ) {
+        match signature {
+            Some(signature) => {
+                // Only the implicit account key is known to belong to the agent instance
+                let public_key = implicit_public_key(agent_instance)
+                    .unwrap_or_else(|| RegistryError::WrongSignature.panic());
+                let signature: [u8; 64] = signature.0.as_slice()
+                    .try_into()
+                    .unwrap_or_else(|_| RegistryError::WrongSignature.panic());
+                let message = instance_consent_message(service_id, operator);
+                ensure(env::ed25519_verify(&signature, &message, &public_key), RegistryError::WrongSignature);
+            }
+            None => {
+                let consent = self.instance_consents
+                    .get(agent_instance)
+                    .unwrap_or_else(|| RegistryError::MissingInstanceConsent.panic());
+                ensure(*consent == InstanceConsent { service_id, operator_id: operator.clone() },
+                    RegistryError::MissingInstanceConsent);
+            }
+        }
+    }
+
+    /// Gives the consent of the calling agent instance to be registered by the operator in the service
+    #[payable]
+    pub fn accept_operator(&mut self, service_id: u32, operator: AccountId) {
+        let agent_instance = env::predecessor_account_id();
+        ensure(operator != agent_instance, RegistryError::WrongAgentInstance);
+
+        // Record current storage usage
+        let initial_storage_usage = env::storage_usage();
+
+        self.instance_consents.insert(agent_instance.clone(), InstanceConsent { service_id, operator_id: operator.clone() });
+        self.instance_consents.flush();
+
+        RegistryEvent::OperatorAccepted { agent_instance: agent_instance.clone(), service_id, operator_id: operator }.emit();
+
+        let storage = env::storage_usage().saturating_sub(initial_storage_usage);
+        // Pay for the storage and refund excessive amount
+        self.refund_deposit_to_account(storage, 0, agent_instance, true);
+    }
+
+    pub fn set_instance_consent_required(&mut self, required: bool) {
+        // Check the ownership
+        ensure(self.owner == env::predecessor_account_id(), RegistryError::Unauthorized);
+
+        self.instance_consent_required = required;
+
+        RegistryEvent::InstanceConsentRequiredUpdated { required }.emit();
+    }
+
+    // Agent instances with public keys are access key members, and the rest are account members
+    fn multisig_members(&self, agent_instances: Vec<AccountId>) -> Vec<MultisigMember> {
+        agent_instances
+            .into_iter()
+            .map(|account_id| match self.agent_instance_keys.get(&account_id) {
+                Some(public_key) => MultisigMember::AccessKey { public_key: public_key.clone() },
+                None => MultisigMember::Account { account_id }
+            })
+            .collect()
+    }
+
     // TODO: needs to be payable?
     #[payable]
     pub fn deploy(
         &mut self,
         service_id: u32,
-        name_multisig: AccountId
+        name_multisig: AccountId,
+        multisig_factory: Option<AccountId>
     ) -> Promise {
+        // Check for the paused state
+        self.require_not_paused();
+
         // Check for service owner
         let owner_id = self.tokens
             .owner_by_id
             .get(&service_id.to_string())
-            .unwrap_or_else(|| env::panic_str("Service not found"));
-        require!(env::predecessor_account_id() == owner_id, "Predecessor must be token owner.");
+            .unwrap_or_else(|| RegistryError::ServiceNotFound.panic());
+        ensure(env::predecessor_account_id() == owner_id, RegistryError::Unauthorized);
 
         // Get the service
-        let service = self.services.get(&service_id).unwrap();
+        let service = self.services.get(&service_id).unwrap_or_else(|| RegistryError::ServiceNotFound.panic());
 
         // Check if the service is already terminated
-        require!(service.state == ServiceState::FinishedRegistration);
+        ensure(service.state == ServiceState::FinishedRegistration, RegistryError::WrongState);
 
         // Check account validity
-        require!(env::is_valid_account_id(name_multisig.as_bytes()));
+        ensure(env::is_valid_account_id(name_multisig.as_bytes()), RegistryError::WrongAccountId);
 
         // Get all agent instances for the multisig
         let mut agent_instances = Vec::new();
@@ -697,45 +1435,95 @@ impl ServiceRegistry {
             //agent_instances.extend(service.agent_params.get(ai).unwrap().instances.iter().cloned());
             let instances = &service.agent_params.get(ai).unwrap().instances;
             for inst in instances.iter() {
-                agent_instances.push(MultisigMember::Account{account_id: inst.clone()});
+                agent_instances.push(inst.clone());
             }
         }
 
-        let is_sub_account = name_multisig.is_sub_account_of(&self.multisig_factory);
+        // Find the multisig interface of the approved factory the multisig was created by, if any
+        let existing_kind = self.multisig_factories
+            .iter()
+            .find(|(factory, _)| name_multisig.is_sub_account_of(factory))
+            .map(|(_, kind)| *kind);
         // Check if the multisig name is a full account of a factory, or a short name for the factory to create it with
         // If not a factory multisig name, create a new multisig instance
-        if !is_sub_account {
+        if existing_kind.is_none() {
             // The multisig account must not have any predecessors
-            require!(name_multisig.get_parent_account_id().is_none());
+            ensure(name_multisig.get_parent_account_id().is_none(), RegistryError::WrongAccountId);
+
+            // Get the requested multisig factory, or the default one
+            let multisig_factory = multisig_factory.unwrap_or_else(|| self.multisig_factory.clone());
+            let kind = *self.multisig_factories
+                .get(&multisig_factory)
+                .unwrap_or_else(|| RegistryError::MultisigFactoryNotApproved.panic());
 
             // Create new multisig
             //log!("Calling external");
-            multisig_factory::ext(self.multisig_factory.clone())
-                .with_static_gas(CREATE_CALL_GAS)
-                .with_attached_deposit(env::attached_deposit())
-                .create(name_multisig.clone(), agent_instances, service.threshold as u64)
-                .then(
-                    // Create a promise to callback create_multisig_callback
-                    Self::ext(env::current_account_id())
-                        .with_static_gas(CALL_GAS)
-                        .create_multisig_callback(service_id, name_multisig.clone())
-                )
+            let create = match kind {
+                MultisigKind::Multisig2 => {
+                    let members = self.multisig_members(agent_instances);
+                    multisig_factory::ext(multisig_factory.clone())
+                        .with_static_gas(CREATE_CALL_GAS)
+                        .with_attached_deposit(env::attached_deposit())
+                        .create(name_multisig.clone(), members, service.threshold as u64)
+                }
+                // The DAO factory returns the creation result the same way as the multisig factory
+                MultisigKind::SputnikDao => sputnik_dao_factory::ext(multisig_factory.clone())
+                    .with_static_gas(CREATE_DAO_CALL_GAS)
+                    .with_attached_deposit(env::attached_deposit())
+                    .create(
+                        name_multisig.clone(),
+                        sputnik::create_args(&name_multisig, service_id, agent_instances, service.threshold)
+                    )
+            };
+            create.then(
+                // Create a promise to callback create_multisig_callback
+                Self::ext(env::current_account_id())
+                    .with_static_gas(CALL_GAS)
+                    .create_multisig_callback(
+                        service_id,
+                        name_multisig.clone(),
+                        multisig_factory,
+                        owner_id,
+                        U128::from(env::attached_deposit().as_yoctonear())
+                    )
+            )
         } else {
             // Deposit must be zero in this scenario
-            require!(env::attached_deposit() == NearToken::from_yoctonear(0), "Deposit is not required");
+            ensure(env::attached_deposit() == NearToken::from_yoctonear(0), RegistryError::DepositNotRequired);
 
-            // Update multisig with the new owners set
-            // Get multisig owners
-            multisig2::ext(name_multisig.clone())
-                .with_static_gas(CALL_GAS)
-                .get_members()
-                // Compare multisig owners with the set of agent instances
-                .then(
-                   // Create a promise to callback update_multisig_callback
-                   Self::ext(env::current_account_id())
-                       .with_static_gas(CALL_GAS)
-                       .update_multisig_callback(service_id, name_multisig.clone(), agent_instances)
-                )
+            match existing_kind {
+                Some(MultisigKind::SputnikDao) => {
+                    // Compare the DAO policy with the set of agent instances and the service threshold
+                    sputnik_dao::ext(name_multisig.clone())
+                        .with_static_gas(CALL_GAS)
+                        .get_policy()
+                        .then(
+                            Self::ext(env::current_account_id())
+                                .with_static_gas(CALL_GAS)
+                                .update_sputnik_dao_callback(service_id, name_multisig.clone(), agent_instances)
+                        )
+                }
+                _ => {
+                    let agent_instances = self.multisig_members(agent_instances);
+                    // Update multisig with the new owners set
+                    // Get multisig owners and the number of confirmations
+                    multisig2::ext(name_multisig.clone())
+                        .with_static_gas(CALL_GAS)
+                        .get_members()
+                        .and(
+                            multisig2::ext(name_multisig.clone())
+                                .with_static_gas(CALL_GAS)
+                                .get_num_confirmations()
+                        )
+                        // Compare multisig owners with the set of agent instances
+                        .then(
+                           // Create a promise to callback update_multisig_callback
+                           Self::ext(env::current_account_id())
+                               .with_static_gas(CALL_GAS)
+                               .update_multisig_callback(service_id, name_multisig.clone(), agent_instances)
+                        )
+                }
+            }
         }
     }
 
@@ -744,59 +1532,348 @@ impl ServiceRegistry {
         &mut self,
         service_id: u32,
         name_multisig: AccountId,
-        #[callback_result] call_result: Result<(), PromiseError>,
-    ) {
-        // Check if the promise succeeded by calling the method outlined in external.rs
-        if call_result.is_err() {
-            env::panic_str("Multisig creation failed");
+        multisig_factory: AccountId,
+        owner_id: AccountId,
+        deposit: U128,
+        #[callback_result] call_result: Result<bool, PromiseError>,
+    ) -> bool {
+        // The factory creates the multisig as its sub-account
+        let multisig: AccountId = format!("{}.{}", name_multisig, multisig_factory)
+            .parse()
+            .unwrap_or_else(|_| RegistryError::WrongAccountId.panic());
+
+        // Check if the multisig factory has created the multisig
+        if !matches!(call_result, Ok(true)) {
+            // The factory returns the deposit to the registry, so it is refunded to the service owner
+            if deposit.0 > 0 {
+                Promise::new(owner_id.clone()).transfer(NearToken::from_yoctonear(deposit.0));
+            }
 
-            // TODO refund
+            // The service stays in the FinishedRegistration state and can be deployed again
+            RegistryEvent::CreateMultisigFailed { service_id, multisig, owner_id, refund: deposit }.emit();
+
+            return false;
         }
 
         // Get the service, record its multisig and update state
-        let service = self.services.get_mut(&service_id).unwrap();
-        service.multisig = Some(name_multisig);
+        let service = self.services.get_mut(&service_id).unwrap_or_else(|| RegistryError::ServiceNotFound.panic());
+
+        // The service could have been terminated before the callback, then it is not deployed.
+        // The deposit has already funded the created multisig account, so there is nothing left to refund
+        if service.state != ServiceState::FinishedRegistration {
+            RegistryEvent::CreateMultisigFailed { service_id, multisig, owner_id, refund: U128::from(0) }.emit();
+
+            return false;
+        }
+
+        service.multisig = Some(multisig.clone());
+        index_service_state(&mut self.services_by_state, service_id, Some(service.state.clone()), ServiceState::Deployed);
         service.state = ServiceState::Deployed;
 
-        // TODO: event
+        RegistryEvent::CreateMultisigWithAgents { service_id, multisig: multisig.clone() }.emit();
+        RegistryEvent::DeployService { service_id, multisig }.emit();
+
+        true
     }
 
+    /// Compares multisig members with agent instances regardless of their order, and the number of confirmations
+    /// with the service threshold. On mismatch the service is not deployed and the difference is reported in the event
     #[private]
     pub fn update_multisig_callback(
         &mut self,
         service_id: u32,
         name_multisig: AccountId,
         agent_instances: Vec<MultisigMember>,
-        #[callback_result] call_result: Result<Vec<MultisigMember>, PromiseError>,
+        #[callback_result] members_result: Result<Vec<MultisigMember>, PromiseError>,
+        #[callback_result] confirmations_result: Result<u32, PromiseError>,
     ) -> bool {
-        // Check if the promise succeeded by calling the method outlined in external.rs
-        if call_result.is_err() {
-            env::panic_str("Multisig update failed");
+        // Check if the promises succeeded by calling the methods outlined in external.rs
+        let (multisig_members, num_confirmations) = match (members_result, confirmations_result) {
+            (Ok(members), Ok(num_confirmations)) => (members, num_confirmations),
+            _ => RegistryError::MultisigCheckFailed.panic()
+        };
 
-            // TODO refund
+        // Get the service, record its multisig and update state
+        let service = self.services.get_mut(&service_id).unwrap_or_else(|| RegistryError::ServiceNotFound.panic());
+
+        // The service could have been terminated or deployed before the callback
+        ensure(service.state == ServiceState::FinishedRegistration, RegistryError::WrongState);
+
+        // Check agent instances vs multisig members as sets
+        let missing: Vec<MultisigMember> = agent_instances
+            .iter()
+            .filter(|&ai| !multisig_members.contains(ai))
+            .cloned()
+            .collect();
+        let extra: Vec<MultisigMember> = multisig_members
+            .iter()
+            .filter(|&mm| !agent_instances.contains(mm))
+            .cloned()
+            .collect();
+
+        if !missing.is_empty() || !extra.is_empty() || num_confirmations != service.threshold {
+            RegistryEvent::MultisigMembersMismatch {
+                service_id,
+                multisig: name_multisig,
+                missing,
+                extra,
+                num_confirmations,
+                threshold: service.threshold
+            }.emit();
+
+            return false;
         }
 
+        service.multisig = Some(name_multisig.clone());
+        // Update service state
+        index_service_state(&mut self.services_by_state, service_id, Some(service.state.clone()), ServiceState::Deployed);
+        service.state = ServiceState::Deployed;
+
+        RegistryEvent::DeployService { service_id, multisig: name_multisig }.emit();
+
+        true
+    }
+
+    #[private]
+    pub fn update_sputnik_dao_callback(
+        &mut self,
+        service_id: u32,
+        name_multisig: AccountId,
+        agent_instances: Vec<AccountId>,
+        #[callback_result] call_result: Result<sputnik::Policy, PromiseError>,
+    ) -> bool {
+        // Check if the DAO policy was received
+        let policy = call_result.unwrap_or_else(|_| RegistryError::MultisigCheckFailed.panic());
+
         // Get the service, record its multisig and update state
-        let service = self.services.get_mut(&service_id).unwrap();
+        let service = self.services.get_mut(&service_id).unwrap_or_else(|| RegistryError::ServiceNotFound.panic());
+
+        // The service could have been terminated or deployed before the callback
+        ensure(service.state == ServiceState::FinishedRegistration, RegistryError::WrongState);
+
+        // The service is not deployed if the DAO council or vote threshold do not match the service,
+        // and the difference is reported in the event the same way as for the multisig
+        if !sputnik::policy_matches(&policy, &agent_instances, service.threshold) {
+            let council = sputnik::council_members(&policy);
+            let missing = agent_instances
+                .iter()
+                .filter(|&ai| !council.contains(ai))
+                .map(|ai| MultisigMember::Account { account_id: ai.clone() })
+                .collect();
+            let extra = council
+                .iter()
+                .filter(|&member| !agent_instances.contains(member))
+                .map(|member| MultisigMember::Account { account_id: member.clone() })
+                .collect();
+            RegistryEvent::MultisigMembersMismatch {
+                service_id,
+                multisig: name_multisig,
+                missing,
+                extra,
+                num_confirmations: sputnik::vote_threshold(&policy),
+                threshold: service.threshold
+            }.emit();
+
+            return false;
+        }
+
+        service.multisig = Some(name_multisig.clone());
+        index_service_state(&mut self.services_by_state, service_id, Some(service.state.clone()), ServiceState::Deployed);
+        service.state = ServiceState::Deployed;
+
+        RegistryEvent::DeployService { service_id, multisig: name_multisig }.emit();
+
+        true
+    }
+
+    /// Replaces the operator agent instance of the deployed service.
+    /// The registry is not a multisig member and does not change the multisig membership itself: the multisig approves
+    /// the replacement by swapping the old agent instance member for the new one beforehand, such that the replacement
+    /// is recorded only when the multisig membership already reflects it
+    #[payable]
+    pub fn replace_agent_instance(
+        &mut self,
+        service_id: u32,
+        old_instance: AccountId,
+        new_instance: AccountId,
+        public_key: Option<PublicKey>,
+        signature: Option<Base64VecU8>,
+        operator_proof: Option<Vec<[u8; 32]>>
+    ) -> Promise {
+        // Check for the paused state
+        self.require_not_paused();
+
+        let operator = env::predecessor_account_id();
+
+        // Get the service
+        let service = self.services.get(&service_id).unwrap_or_else(|| RegistryError::ServiceNotFound.panic());
 
-        let mut success = false;
-        // Check agent instances vs multisig members
-        let multisig_members = call_result.unwrap();
-        let matching = agent_instances.iter().zip(multisig_members.iter()).filter(|&(ai, mm)| ai == mm).count();
-        //let matching = agent_instances.iter().zip(multisig_members.iter()).filter(|&(ai, mm)| match ai {MultisigMember::Account(value) => value == mm, _ => {false}}).count();
-        if matching == agent_instances.len() && matching == multisig_members.len() {
-            service.multisig = Some(name_multisig);
-            // Update service state
-            service.state = ServiceState::Deployed;
-            success = true;
+        // Check the service state
+        ensure(service.state == ServiceState::Deployed, RegistryError::WrongState);
+
+        // Check that the old agent instance is registered in this service by the operator
+        ensure(service.agent_instances.contains_key(&old_instance), RegistryError::WrongAgentInstance);
+        ensure(self.agent_instance_operators.get(&old_instance) == Some(&operator), RegistryError::WrongAgentInstance);
+        let multisig = service.multisig.clone().unwrap_or_else(|| RegistryError::WrongMultisig.panic());
+
+        // Check the new agent instance the same way as the registered ones
+        ensure(!self.agent_instance_operators.contains_key(&new_instance), RegistryError::DuplicateInstance);
+        self.check_new_instances(
+            service_id,
+            &operator,
+            std::slice::from_ref(&new_instance),
+            std::slice::from_ref(&public_key),
+            &[signature],
+            operator_proof
+        );
+        let deposit = U128::from(env::attached_deposit().as_yoctonear());
+
+        // Find the multisig interface of the approved factory the multisig was created by
+        let kind = self.multisig_factories
+            .iter()
+            .find(|(factory, _)| multisig.is_sub_account_of(factory))
+            .map_or(MultisigKind::Multisig2, |(_, kind)| *kind);
+        match kind {
+            MultisigKind::SputnikDao => sputnik_dao::ext(multisig)
+                .with_static_gas(CALL_GAS)
+                .get_policy()
+                .then(
+                    Self::ext(env::current_account_id())
+                        .with_static_gas(CALL_GAS)
+                        .replace_sputnik_dao_instance_callback(service_id, operator, old_instance, new_instance, public_key, deposit)
+                ),
+            MultisigKind::Multisig2 => multisig2::ext(multisig)
+                .with_static_gas(CALL_GAS)
+                .get_members()
+                .then(
+                    Self::ext(env::current_account_id())
+                        .with_static_gas(CALL_GAS)
+                        .replace_agent_instance_callback(service_id, operator, old_instance, new_instance, public_key, deposit)
+                )
+        }
+    }
+
+    #[private]
+    pub fn replace_agent_instance_callback(
+        &mut self,
+        service_id: u32,
+        operator: AccountId,
+        old_instance: AccountId,
+        new_instance: AccountId,
+        public_key: Option<PublicKey>,
+        deposit: U128,
+        #[callback_result] call_result: Result<Vec<MultisigMember>, PromiseError>,
+    ) -> bool {
+        // The multisig must have the new agent instance member instead of the old one
+        let old_member = self.multisig_members(vec![old_instance.clone()]).remove(0);
+        let new_member = match &public_key {
+            Some(public_key) => MultisigMember::AccessKey { public_key: public_key.clone() },
+            None => MultisigMember::Account { account_id: new_instance.clone() }
+        };
+        let approved = call_result.is_ok_and(|members| members.contains(&new_member) && !members.contains(&old_member));
+
+        self.finalize_replacement(service_id, operator, old_instance, new_instance, public_key, deposit, approved)
+    }
+
+    #[private]
+    pub fn replace_sputnik_dao_instance_callback(
+        &mut self,
+        service_id: u32,
+        operator: AccountId,
+        old_instance: AccountId,
+        new_instance: AccountId,
+        public_key: Option<PublicKey>,
+        deposit: U128,
+        #[callback_result] call_result: Result<sputnik::Policy, PromiseError>,
+    ) -> bool {
+        // The DAO council must have the new agent instance account instead of the old one,
+        // and its public key is recorded the same way as on the registration
+        let approved = call_result.is_ok_and(|policy| sputnik::is_council_member(&policy, &new_instance)
+            && !sputnik::is_council_member(&policy, &old_instance));
+
+        self.finalize_replacement(service_id, operator, old_instance, new_instance, public_key, deposit, approved)
+    }
+
+    // Swaps the agent instance in all the service and registry maps, or refunds the deposit if not approved
+    fn finalize_replacement(
+        &mut self,
+        service_id: u32,
+        operator: AccountId,
+        old_instance: AccountId,
+        new_instance: AccountId,
+        public_key: Option<PublicKey>,
+        deposit: U128,
+        approved: bool
+    ) -> bool {
+        let service = self.services.get_mut(&service_id).unwrap_or_else(|| RegistryError::ServiceNotFound.panic());
+
+        // Re-check the replacement conditions, as they could have changed before the callback
+        let valid = approved
+            && service.state == ServiceState::Deployed
+            && service.agent_instances.contains_key(&old_instance)
+            && self.agent_instance_operators.get(&old_instance) == Some(&operator)
+            && !self.agent_instance_operators.contains_key(&new_instance);
+        if !valid {
+            if deposit.0 > 0 {
+                Promise::new(operator.clone()).transfer(NearToken::from_yoctonear(deposit.0));
+            }
+
+            RegistryEvent::ReplaceInstanceFailed { service_id, operator_id: operator, old_instance, new_instance }.emit();
+
+            return false;
         }
 
-        // Revert if the multisig members comparison fails
-        require!(success);
+        // Record current storage usage
+        let initial_storage_usage = env::storage_usage();
 
-        // TODO: event
+        // Swap agent instances in the service maps
+        let agent_id = service.agent_instances.remove(&old_instance).unwrap();
+        service.agent_instances.insert(new_instance.clone(), agent_id);
+        let agent_params = service.agent_params.get_mut(&agent_id).unwrap();
+        if let Some(index) = agent_params.instances.iter().position(|instance| *instance == old_instance) {
+            agent_params.instances.replace(index as u32, new_instance.clone());
+            agent_params.instances.flush();
+        }
+        let operator_data = service.operators.get_mut(&operator).unwrap();
+        if let Some(index) = operator_data.instances.iter().position(|instance| *instance == old_instance) {
+            operator_data.instances.replace(index as u32, new_instance.clone());
+            operator_data.instances.flush();
+        }
+        service.agent_instances.flush();
+        service.agent_params.flush();
+        service.operators.flush();
+
+        // Swap agent instances in the registry maps
+        self.agent_instance_operators.remove(&old_instance);
+        self.agent_instance_operators.insert(new_instance.clone(), operator.clone());
+        self.agent_instance_keys.remove(&old_instance);
+        if let Some(public_key) = public_key {
+            self.agent_instance_keys.insert(new_instance.clone(), public_key);
+        }
+        // The consent of the new agent instance is consumed only by the recorded replacement
+        consume_instance_consent(&mut self.instance_consents, service_id, &operator, &new_instance);
+        self.agent_instance_operators.flush();
+        self.agent_instance_keys.flush();
+        self.instance_consents.flush();
+
+        RegistryEvent::ReplaceInstance {
+            service_id,
+            operator_id: operator.clone(),
+            old_instance,
+            new_instance,
+            agent_id
+        }.emit();
+
+        // Pay for the increased storage from the deposit, or refund the released storage
+        let final_storage_usage = env::storage_usage();
+        let attached_deposit = NearToken::from_yoctonear(deposit.0);
+        if final_storage_usage > initial_storage_usage {
+            self.refund_deposit_from(final_storage_usage - initial_storage_usage, 0, operator, true, attached_deposit);
+        } else {
+            self.refund_deposit_from(initial_storage_usage - final_storage_usage, 0, operator, false, attached_deposit);
+        }
 
-        success
+        true
     }
 
     pub fn slash(
@@ -806,22 +1883,22 @@ impl ServiceRegistry {
         service_id: u32
     ) {
         // Check array lengths
-        require!(amounts.len() == agent_instances.len());
+        ensure(amounts.len() == agent_instances.len(), RegistryError::WrongArrayLength);
 
         // Get the service
-        let service = self.services.get_mut(&service_id).unwrap();
+        let service = self.services.get_mut(&service_id).unwrap_or_else(|| RegistryError::ServiceNotFound.panic());
 
         // Check if the service is already terminated
-        require!(service.state == ServiceState::Deployed);
+        ensure(service.state == ServiceState::Deployed, RegistryError::WrongState);
 
         // Only the multisig of a correspondent address can slash its agent instances
-        require!(service.multisig.clone().unwrap() == env::predecessor_account_id());
+        ensure(service.multisig.as_ref() == Some(&env::predecessor_account_id()), RegistryError::WrongMultisig);
 
-        // Get service token
-        let token = service.token.clone().unwrap();
+        // Get service token kind
+        let token = TokenKind::from(service.token.clone());
 
         // Get slashed funds map
-        let slashed_funds = self.slashed_funds.get_mut(&token).unwrap();
+        let slashed_funds = self.slashed_funds.get_mut(&token).unwrap_or_else(|| RegistryError::TokenNotRegistered.panic());
 
         // Traverse all agent instances
         for i in 0..agent_instances.len() {
@@ -829,24 +1906,29 @@ impl ServiceRegistry {
             let agent_instance = agent_instances[i].clone();
 
             // Get the operator and its balance
-            let operator = self.agent_instance_operators.get(&agent_instance).unwrap();
-            let operator_data = service.operators.get_mut(operator).unwrap();
+            let operator = self.agent_instance_operators.get(&agent_instance).unwrap_or_else(|| RegistryError::WrongAgentInstance.panic());
+            let operator_data = service.operators.get_mut(operator).unwrap_or_else(|| RegistryError::OperatorNotFound.panic());
             let mut balance = operator_data.balance;
 
             // Slash the balance of the operator, make sure it does not go below zero
-            if amount >= balance {
-                // We cannot add to the slashed amount more than the balance of the operator
-                *slashed_funds = (*slashed_funds).saturating_add(balance.into());
-                balance = 0;
-            } else {
-                *slashed_funds = (*slashed_funds).saturating_add(amount.into());
-                balance = balance.saturating_sub(amount.into());
+            let slashed_amount = if amount >= balance { balance } else { amount };
+            // We cannot add to the slashed amount more than the balance of the operator
+            *slashed_funds = (*slashed_funds).saturating_add(slashed_amount);
+            balance = balance.saturating_sub(slashed_amount);
+            // The slashed native amount is no longer accounted as bonded in the registry
+            if token == TokenKind::Native {
+                self.balance = self.balance.saturating_sub(slashed_amount);
             }
 
             // Update the operator balance value
             operator_data.balance = balance;
 
-            // TODO event
+            RegistryEvent::Slash {
+                service_id,
+                operator_id: operator.clone(),
+                agent_instance,
+                amount: U128::from(slashed_amount)
+            }.emit();
         }
     }
 
@@ -860,22 +1942,59 @@ impl ServiceRegistry {
         let owner_id = self.tokens
             .owner_by_id
             .get(&service_id.to_string())
-            .unwrap_or_else(|| env::panic_str("Service not found"));
-        require!(env::predecessor_account_id() == owner_id, "Predecessor must be token owner.");
+            .unwrap_or_else(|| RegistryError::ServiceNotFound.panic());
+        ensure(env::predecessor_account_id() == owner_id, RegistryError::Unauthorized);
+
+        self.terminate_service(service_id, owner_id);
+    }
+
+    /// Terminates the service which registration is not finished before its deadline.
+    /// Callable by anyone, such that operators can unbond and the service owner gets the security deposit back
+    pub fn expire_registration(&mut self, service_id: u32) {
+        let owner_id = self.tokens
+            .owner_by_id
+            .get(&service_id.to_string())
+            .unwrap_or_else(|| RegistryError::ServiceNotFound.panic());
+
+        // Get the service
+        let service = self.services.get(&service_id).unwrap_or_else(|| RegistryError::ServiceNotFound.panic());
+
+        // Check the service state
+        ensure(service.state == ServiceState::ActiveRegistration, RegistryError::WrongState);
+
+        // Check that the registration deadline has passed
+        let deadline = self.registration_deadlines
+            .get(&service_id)
+            .copied()
+            .unwrap_or_else(|| RegistryError::RegistrationNotExpired.panic());
+        ensure(block_timestamp_secs() > deadline, RegistryError::RegistrationNotExpired);
+
+        RegistryEvent::RegistrationExpired { service_id, owner_id: owner_id.clone(), deadline }.emit();
+
+        self.terminate_service(service_id, owner_id);
+    }
 
+    // Terminates the service and refunds the security deposit and the released storage to the service owner
+    fn terminate_service(&mut self, service_id: u32, owner_id: AccountId) {
         // Record current storage usage
         let initial_storage_usage = env::storage_usage();
 
         // Get the service
-        let service = self.services.get_mut(&service_id).unwrap();
+        let service = self.services.get_mut(&service_id).unwrap_or_else(|| RegistryError::ServiceNotFound.panic());
 
         // Check if the service is already terminated
-        require!(service.state != ServiceState::PreRegistration && service.state != ServiceState::TerminatedBonded);
+        ensure(service.state != ServiceState::PreRegistration && service.state != ServiceState::TerminatedBonded, RegistryError::WrongState);
+
+        // The registration deadline is no longer relevant
+        self.registration_deadlines.remove(&service_id);
+        self.registration_deadlines.flush();
 
         // Define the state of the service depending on the number of bonded agent instances
         if service.num_agent_instances > 0 {
+            index_service_state(&mut self.services_by_state, service_id, Some(service.state.clone()), ServiceState::TerminatedBonded);
             service.state = ServiceState::TerminatedBonded;
         } else {
+            index_service_state(&mut self.services_by_state, service_id, Some(service.state.clone()), ServiceState::PreRegistration);
             service.state = ServiceState::PreRegistration;
         }
 
@@ -890,26 +2009,28 @@ impl ServiceRegistry {
         let mut refund = service.security_deposit;
         self.balance = self.balance.saturating_sub(refund.into());
 
+        RegistryEvent::TerminateService {
+            service_id,
+            owner_id: owner_id.clone(),
+            refund: U128::from(refund)
+        }.emit();
+
         // TODO: Calculate refund of freed storage
         //log!("initial storage usage {}", initial_storage_usage);
         //log!("storage usage after {}", env::storage_usage());
 
-        if service.token.is_some() {
+        if let Some(token) = service.token.clone() {
             // Send the token refund back to the service owner
-            ext_ft_core::ext(service.token.clone().unwrap())
-                .with_static_gas(CALL_GAS)
-                .ft_transfer(owner_id, U128::from(refund), None);
+            self.transfer_token(token, owner_id.clone(), refund, false);
 
             // Zero the refund since it has been already sent back
             refund = 0;
         }
 
         // Decreased storage
-        let storage = initial_storage_usage - env::storage_usage();
+        let storage = initial_storage_usage.saturating_sub(env::storage_usage());
         // Send the deposit back to the service owner
-        self.refund_deposit_to_account(storage, refund, env::predecessor_account_id(), false);
-
-        // TODO: event
+        self.refund_deposit_to_account(storage, refund, owner_id, false);
     }
 
     #[payable]
@@ -921,13 +2042,13 @@ impl ServiceRegistry {
         let initial_storage_usage = env::storage_usage();
 
         // Get the service
-        let service = self.services.get_mut(&service_id).unwrap();
+        let service = self.services.get_mut(&service_id).unwrap_or_else(|| RegistryError::ServiceNotFound.panic());
 
         // Check the service state
-        require!(service.state == ServiceState::TerminatedBonded);
+        ensure(service.state == ServiceState::TerminatedBonded, RegistryError::WrongState);
 
         // Get the operator struct
-        let operator_data = service.operators.get(&operator).unwrap_or_else(|| env::panic_str("Operator has no instances"));
+        let operator_data = service.operators.get(&operator).unwrap_or_else(|| RegistryError::OperatorNotFound.panic());
 
         // Decrease the total number of agent instances in a service
         service.num_agent_instances -= operator_data.instances.len() as u32;
@@ -935,6 +2056,7 @@ impl ServiceRegistry {
         // When number of instances is equal to zero, all the operators have unbonded and the service is moved into
         // the PreRegistration state, from where it can be updated / initiate registration / get deployed again
         if service.num_agent_instances == 0 {
+            index_service_state(&mut self.services_by_state, service_id, Some(service.state.clone()), ServiceState::PreRegistration);
             service.state = ServiceState::PreRegistration;
         }
 
@@ -950,9 +2072,11 @@ impl ServiceRegistry {
 
             // Remove the relevant data
             self.agent_instance_operators.remove(operator_data.instances.get(i).unwrap());
+            self.agent_instance_keys.remove(operator_data.instances.get(i).unwrap());
             service.agent_instances.remove(operator_data.instances.get(i).unwrap());
         }
         self.agent_instance_operators.flush();
+        self.agent_instance_keys.flush();
         service.agent_instances.flush();
 
         // Check if the refund exceeds operator's balance
@@ -961,18 +2085,29 @@ impl ServiceRegistry {
             refund = operator_data.balance;
         }
 
-        // Remove the operator data from current service
-        service.operators.remove(&operator);
+        // Remove the operator data from current service, keeping the whitelisting status set by the service owner
+        if operator_data.whitelisted {
+            let operator_data = service.operators.get_mut(&operator).unwrap();
+            operator_data.balance = 0;
+            operator_data.instances.clear();
+            operator_data.instances.flush();
+        } else {
+            service.operators.remove(&operator);
+        }
         service.operators.flush();
 
         // Update registry balance
         self.balance = self.balance.saturating_sub(refund.into());
 
-        if service.token.is_some() {
-            // Send the token refund back to the service owner
-            ext_ft_core::ext(service.token.clone().unwrap())
-                .with_static_gas(CALL_GAS)
-                .ft_transfer(operator, U128::from(refund), None);
+        RegistryEvent::OperatorUnbond {
+            service_id,
+            operator_id: operator.clone(),
+            refund: U128::from(refund)
+        }.emit();
+
+        if let Some(token) = service.token.clone() {
+            // Send the token refund back to the operator
+            self.transfer_token(token, operator, refund, false);
 
             // Zero the refund since it has been already sent back
             refund = 0;
@@ -982,38 +2117,226 @@ impl ServiceRegistry {
 //         log!("storage usage after {}", env::storage_usage());
         // Increased storage
         // TODO: need to correctly recalculate the storage decrease
-        let storage = initial_storage_usage - env::storage_usage();
+        let storage = initial_storage_usage.saturating_sub(env::storage_usage());
         // Refund storage, bond cost and the rest
         self.refund_deposit_to_account(storage, refund, env::predecessor_account_id(), false);
+    }
+
+    /// Unregisters operator agent instances while the service is in the ActiveRegistration state,
+    /// freeing their slots and refunding their bonds and the released storage
+    #[payable]
+    pub fn unregister_agents(&mut self, service_id: u32, agent_instances: Vec<AccountId>) {
+        // Check array length
+        ensure(!agent_instances.is_empty(), RegistryError::WrongArrayLength);
+
+        // Get the operator account
+        let operator = env::predecessor_account_id();
+
+        // Record current storage usage
+        let initial_storage_usage = env::storage_usage();
+
+        // Get the service
+        let service = self.services.get_mut(&service_id).unwrap_or_else(|| RegistryError::ServiceNotFound.panic());
+
+        // Check the service state
+        ensure(service.state == ServiceState::ActiveRegistration, RegistryError::WrongState);
+
+        // Get the operator struct
+        let operator_data = service.operators.get_mut(&operator).unwrap_or_else(|| RegistryError::OperatorNotFound.panic());
+
+        let mut refund: u128 = 0;
+        for agent_instance in agent_instances {
+            // Only the operator of the agent instance is able to unregister it
+            ensure(self.agent_instance_operators.get(&agent_instance) == Some(&operator), RegistryError::WrongAgentInstance);
+
+            // Get agent id by the agent instance and free its slot
+            let agent_id = service.agent_instances
+                .remove(&agent_instance)
+                .unwrap_or_else(|| RegistryError::WrongAgentInstance.panic());
+            let agent_params = service.agent_params.get_mut(&agent_id).unwrap_or_else(|| RegistryError::AgentNotFound.panic());
+            if let Some(index) = agent_params.instances.iter().position(|instance| *instance == agent_instance) {
+                agent_params.instances.swap_remove(index as u32);
+                agent_params.instances.flush();
+            }
+            if let Some(index) = operator_data.instances.iter().position(|instance| *instance == agent_instance) {
+                operator_data.instances.swap_remove(index as u32);
+                operator_data.instances.flush();
+            }
+
+            // Remove the agent instance from the registry
+            self.agent_instance_operators.remove(&agent_instance);
+            self.agent_instance_keys.remove(&agent_instance);
+
+            // Decrease the total number of agent instances in a service
+            service.num_agent_instances -= 1;
+
+            // Add bond to the refund
+            refund = refund.saturating_add(agent_params.bond);
+
+            RegistryEvent::UnregisterInstance {
+                service_id,
+                operator_id: operator.clone(),
+                agent_instance,
+                agent_id,
+                bond: U128::from(agent_params.bond)
+            }.emit();
+        }
+        self.agent_instance_operators.flush();
+        self.agent_instance_keys.flush();
+        service.agent_instances.flush();
+        service.agent_params.flush();
+
+        // The refund must not exceed the operator balance
+        if refund > operator_data.balance {
+            refund = operator_data.balance;
+        }
+        operator_data.balance -= refund;
+
+        // Remove the operator data without agent instances, unless it keeps the whitelisting status
+        if operator_data.instances.is_empty() && !operator_data.whitelisted {
+            service.operators.remove(&operator);
+        }
+        service.operators.flush();
+
+        if let Some(token) = service.token.clone() {
+            // Send the token refund back to the operator
+            self.transfer_token(token, operator.clone(), refund, false);
+
+            // Zero the refund since it has been already sent back
+            refund = 0;
+        } else {
+            // Update registry balance, which only accounts native bonds
+            self.balance = self.balance.saturating_sub(refund);
+        }
+
+        // Decreased storage
+        let storage = initial_storage_usage.saturating_sub(env::storage_usage());
+        // Refund storage, bond cost and the rest
+        self.refund_deposit_to_account(storage, refund, operator, false);
+    }
+
+    // Sends tokens to the receiver and returns them into the receiver balance if the transfer fails.
+    // The receiver token storage is registered if missing, charged within the storage usage of the calling method,
+    // and is kept until the transfer is settled. With withdraw_storage, it is released only after the transfer succeeds
+    fn transfer_token(&mut self, token: AccountId, receiver_id: AccountId, amount: u128, withdraw_storage: bool) -> Promise {
+        let balances = self.all_token_balances
+            .get_mut(&token)
+            .unwrap_or_else(|| RegistryError::TokenNotRegistered.panic());
+        if !balances.contains_key(&receiver_id) {
+            balances.insert(receiver_id.clone(), 0);
+            balances.flush();
+        }
+        self.all_token_balances.flush();
+
+        // Count the transfer until it is settled
+        *self.pending_token_transfers.entry(receiver_id.clone()).or_insert(0) += 1;
+
+        ext_ft_core::ext(token.clone())
+            .with_attached_deposit(NearToken::from_yoctonear(1))
+            .with_static_gas(CALL_GAS)
+            .ft_transfer(receiver_id.clone(), U128::from(amount), None)
+            .then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(CALL_GAS)
+                    .resolve_token_transfer(token, receiver_id, U128::from(amount), withdraw_storage)
+            )
+    }
+
+    #[private]
+    pub fn resolve_token_transfer(
+        &mut self,
+        token: AccountId,
+        receiver_id: AccountId,
+        amount: U128,
+        withdraw_storage: bool,
+        #[callback_result] call_result: Result<(), PromiseError>,
+    ) -> bool {
+        // The transfer is settled
+        match self.pending_token_transfers.get_mut(&receiver_id) {
+            Some(count) if *count > 1 => *count -= 1,
+            _ => {
+                self.pending_token_transfers.remove(&receiver_id);
+            }
+        }
+
+        if call_result.is_ok() {
+            // Release the receiver token storage, unless the balance was credited again or other transfers are pending
+            let balance = self.all_token_balances.get(&token).and_then(|balances| balances.get(&receiver_id).copied());
+            if withdraw_storage && balance == Some(0) && !self.pending_token_transfers.contains_key(&receiver_id) {
+                self.internal_storage_withdraw(token, receiver_id);
+            }
+            return true;
+        }
+
+        // Re-credit the receiver balance such that the amount can be withdrawn later.
+        // The balance storage is still paid for, as it is kept until the transfer is settled
+        let balance = self.all_token_balances
+            .get_mut(&token)
+            .unwrap_or_else(|| RegistryError::TokenNotRegistered.panic())
+            .get_mut(&receiver_id)
+            .unwrap_or_else(|| RegistryError::AccountNotRegistered.panic());
+        *balance = (*balance).saturating_add(amount.0);
+        self.all_token_balances.flush();
+
+        RegistryEvent::TokenTransferFailed { account_id: receiver_id, token, amount }.emit();
+
+        false
+    }
+
+    #[private]
+    pub fn resolve_drain(
+        &mut self,
+        token: AccountId,
+        drainer_id: AccountId,
+        amount: U128,
+        #[callback_result] call_result: Result<(), PromiseError>,
+    ) -> bool {
+        if call_result.is_ok() {
+            return true;
+        }
+
+        // Return the amount into slashed funds such that it can be drained again
+        let slashed_funds = self.slashed_funds.get_mut(&TokenKind::FungibleToken(token.clone())).unwrap();
+        *slashed_funds = (*slashed_funds).saturating_add(amount.0);
+        self.slashed_funds.flush();
+
+        RegistryEvent::TokenTransferFailed { account_id: drainer_id, token, amount }.emit();
 
-        // TODO: event
+        false
     }
 
     // TODO Shall this be payable as 1 yocto is needed for?
-    pub fn drain(&mut self, token: AccountId) {
-        // Check the ownership
-        require!(self.owner == env::predecessor_account_id());
+    pub fn drain(&mut self, token: TokenKind) {
+        self.require_role(Role::Drainer);
 
-        let amount = self.slashed_funds.get_mut(&token).unwrap();
+        let amount = self.slashed_funds.get_mut(&token).unwrap_or_else(|| RegistryError::TokenNotRegistered.panic());
         let transfer_amount = *amount;
 
-        // Check for native token
-        if token == "near.near" {
-            // TODO: 1 or 0 here?
-            if transfer_amount > 1 {
+        match token.clone() {
+            TokenKind::Native => if transfer_amount > 0 {
                 *amount = 0;
                 Promise::new(env::predecessor_account_id()).transfer(NearToken::from_yoctonear(transfer_amount));
             }
-        } else {
-            if transfer_amount > 0 {
+            TokenKind::FungibleToken(token_id) => if transfer_amount > 0 {
                 *amount = 0;
-                ext_ft_core::ext(token)
+                ext_ft_core::ext(token_id.clone())
+                    .with_attached_deposit(NearToken::from_yoctonear(1))
                     .with_static_gas(CALL_GAS)
-                    .ft_transfer(env::predecessor_account_id(), U128::from(transfer_amount), None);
+                    .ft_transfer(env::predecessor_account_id(), U128::from(transfer_amount), None)
+                    .then(
+                        // Return the amount into slashed funds if the transfer fails
+                        Self::ext(env::current_account_id())
+                            .with_static_gas(CALL_GAS)
+                            .resolve_drain(token_id, env::predecessor_account_id(), U128::from(transfer_amount))
+                    );
             }
         }
 
-        // TODO: event
+        RegistryEvent::Drain {
+            drainer_id: env::predecessor_account_id(),
+            token,
+            amount: U128::from(transfer_amount)
+        }.emit();
     }
 
     pub fn withdraw(&mut self, token: AccountId, amount: u128, withdraw_storage: bool) {
@@ -1022,25 +2345,27 @@ impl ServiceRegistry {
         if let Some(b) = self
             .all_token_balances
             .get_mut(&token)
-            .unwrap_or_else(|| env::panic_str("Token not registered"))
+            .unwrap_or_else(|| RegistryError::TokenNotRegistered.panic())
             .get_mut(&sender_id)
         {
             // Set the balance to zero
-            require!(*b >= amount, "Insufficient token balance");
+            ensure(*b >= amount, RegistryError::InsufficientTokenBalance);
             *b = (*b).saturating_sub(amount);
 
-            // Send tokens back to the sender
-            ext_ft_core::ext(token.clone())
-                .with_static_gas(CALL_GAS)
-                .ft_transfer(sender_id.clone(), U128::from(amount), None);
+            // Token storage can only be withdrawn with the zero balance
+            ensure(!withdraw_storage || *b == 0, RegistryError::NonZeroTokenBalance);
 
-            // Withdraw token storage for the sender on request
-            if withdraw_storage {
-                self.storage_withdraw(token);
-            }
+            // Send tokens back to the sender, and withdraw token storage on request once the transfer succeeds
+            self.transfer_token(token.clone(), sender_id.clone(), amount, withdraw_storage);
+
+            RegistryEvent::Withdraw {
+                account_id: sender_id,
+                token,
+                amount: U128::from(amount)
+            }.emit();
         } else {
             // Fail otherwise
-            env::panic_str("Sender not registered");
+            RegistryError::AccountNotRegistered.panic();
         }
     }
 
@@ -1056,9 +2381,9 @@ impl ServiceRegistry {
         let token_balances = self
             .all_token_balances
             // Get token map
-            .entry(token)
+            .entry(token.clone())
             // or create a new one if not
-            .or_insert(LookupMap::new(StorageKey::TokenBalances));
+            .or_insert(LookupMap::new(StorageKey::TokenBalancesPerToken { token }));
 
         // Check if the service owner is registered
         if !token_balances.contains_key(&sender_id) {
@@ -1073,23 +2398,31 @@ impl ServiceRegistry {
     }
 
     pub fn storage_withdraw(&mut self, token: AccountId) {
-        let initial_storage_usage = env::storage_usage();
-
         let account_id = env::predecessor_account_id();
 
+        // The token storage is kept until all the transfers to the account are settled
+        ensure(!self.pending_token_transfers.contains_key(&account_id), RegistryError::PendingTokenTransfer);
+
+        self.internal_storage_withdraw(token, account_id);
+    }
+
+    // Removes the zero token balance of the account and sends the released storage cost back to the account
+    fn internal_storage_withdraw(&mut self, token: AccountId, account_id: AccountId) {
+        let initial_storage_usage = env::storage_usage();
+
         // Get the token balance
         if let Some(b) = self
             .all_token_balances
             .get_mut(&token)
-            .unwrap_or_else(|| env::panic_str("Token not registered"))
+            .unwrap_or_else(|| RegistryError::TokenNotRegistered.panic())
             .get_mut(&account_id)
         {
             // The balance must be zero
-            require!(*b == 0, "Token balance must be zero");
+            ensure(*b == 0, RegistryError::NonZeroTokenBalance);
 
         } else {
             // Fail otherwise
-            env::panic_str("Sender not registered");
+            RegistryError::AccountNotRegistered.panic();
         }
 
         // Remove account storage associated with the token
@@ -1108,18 +2441,17 @@ impl ServiceRegistry {
         let owner_id = self.tokens
             .owner_by_id
             .get(&service_id.to_string())
-            .unwrap_or_else(|| env::panic_str("Service not found"));
-        require!(env::predecessor_account_id() == owner_id, "Predecessor must be token owner.");
+            .unwrap_or_else(|| RegistryError::ServiceNotFound.panic());
+        ensure(env::predecessor_account_id() == owner_id, RegistryError::Unauthorized);
 
         // Get the service
         // TODO Check if service id exists?
-        let service = self.services.get_mut(&service_id).unwrap();
+        let service = self.services.get_mut(&service_id).unwrap_or_else(|| RegistryError::ServiceNotFound.panic());
 
         // Set the operator address check requirement
         service.operators_check = set_check;
 
-        // TODO: event
-        //emit SetOperatorsCheck(msg.sender, serviceId, setCheck);
+        RegistryEvent::SetOperatorsCheck { service_id, owner_id, check: set_check }.emit();
     }
 
     // Call by the service owner
@@ -1129,19 +2461,19 @@ impl ServiceRegistry {
         let owner_id = self.tokens
             .owner_by_id
             .get(&service_id.to_string())
-            .unwrap_or_else(|| env::panic_str("Service not found"));
-        require!(env::predecessor_account_id() == owner_id, "Predecessor must be token owner.");
+            .unwrap_or_else(|| RegistryError::ServiceNotFound.panic());
+        ensure(env::predecessor_account_id() == owner_id, RegistryError::Unauthorized);
 
         // Check array lengths
-        require!(operators.len() > 0);
-        require!(operators.len() == statuses.len());
+        ensure(!operators.is_empty(), RegistryError::WrongArrayLength);
+        ensure(operators.len() == statuses.len(), RegistryError::WrongArrayLength);
 
         // Record current storage usage
         let initial_storage_usage = env::storage_usage();
 
         // Get the service
         // TODO Check if service id exists?
-        let service = self.services.get_mut(&service_id).unwrap();
+        let service = self.services.get_mut(&service_id).unwrap_or_else(|| RegistryError::ServiceNotFound.panic());
 
         // Set the operator address check requirement
         service.operators_check = set_check;
@@ -1156,35 +2488,216 @@ impl ServiceRegistry {
                 // or create a new one if not
                 .or_insert(OperatorData{
                     balance: 0 as u128,
-                    instances: Vector::new(StorageKey::AgentInstance),
+                    instances: Vector::new(StorageKey::ServiceOperatorInstance { service_id, operator: operators[i].clone() }),
                     whitelisted: true
                 });
             operator_data.whitelisted = statuses[i];
         }
         service.operators.flush();
 
-        // TODO: event
-        //emit OperatorsWhitelistUpdated(msg.sender, serviceId, operators, statuses, setCheck);
+        RegistryEvent::OperatorsWhitelistUpdated {
+            service_id,
+            owner_id,
+            operators,
+            statuses,
+            check: set_check
+        }.emit();
 
         let storage = env::storage_usage() - initial_storage_usage;
         // Pay for the storage and refund excessive amount
         self.refund_deposit_to_account(storage, 0, env::predecessor_account_id(), true);
     }
 
+    /// Sets the merkle root of allowed operators, such that operators are able to register with the merkle proof
+    /// instead of being whitelisted one by one
+    // Call by the service owner
+    #[payable]
+    pub fn set_operators_root(&mut self, service_id: u32, root: Option<[u8; 32]>) {
+        // Check for service owner
+        let owner_id = self.tokens
+            .owner_by_id
+            .get(&service_id.to_string())
+            .unwrap_or_else(|| RegistryError::ServiceNotFound.panic());
+        ensure(env::predecessor_account_id() == owner_id, RegistryError::Unauthorized);
+
+        // Record current storage usage
+        let initial_storage_usage = env::storage_usage();
+
+        match root {
+            Some(root) => self.operators_roots.insert(service_id, root),
+            None => self.operators_roots.remove(&service_id)
+        };
+        self.operators_roots.flush();
+
+        RegistryEvent::OperatorsRootUpdated { service_id, owner_id: owner_id.clone(), root: root.map(hex::encode) }.emit();
+
+        let final_storage_usage = env::storage_usage();
+        if final_storage_usage >= initial_storage_usage {
+            // Pay for the storage and refund excessive amount
+            self.refund_deposit_to_account(final_storage_usage - initial_storage_usage, 0, owner_id, true);
+        } else {
+            // Send the storage released cost back to the service owner
+            self.refund_deposit_to_account(initial_storage_usage - final_storage_usage, 0, owner_id, false);
+        }
+    }
+
+    /// Moves service nested collections created with legacy shared storage prefixes under per-service prefixes.
+    /// Operators are not iterable on-chain, so the ones without agent instances (whitelisted only)
+    /// need to be provided explicitly. Legacy entries are not removed as they might be shared with other services.
+    // Call by the registry owner
+    #[payable]
+    pub fn migrate_service_storage(&mut self, service_id: u32, operators: Vec<AccountId>) {
+        // Check the ownership
+        ensure(self.owner == env::predecessor_account_id(), RegistryError::Unauthorized);
+
+        // Record current storage usage
+        let initial_storage_usage = env::storage_usage();
+
+        // Get the service
+        let service = self.services.get_mut(&service_id).unwrap_or_else(|| RegistryError::ServiceNotFound.panic());
+
+        // Copy config hashes
+        let config_hashes: Vec<[u8; 32]> = service.config_hashes.iter().cloned().collect();
+        service.config_hashes = Vector::new(StorageKey::ServiceConfigHash { service_id });
+        service.config_hashes.extend(config_hashes);
+        service.config_hashes.flush();
+
+        // Copy agent ids
+        let agent_ids: Vec<u32> = service.agent_ids.iter().cloned().collect();
+        service.agent_ids = Vector::new(StorageKey::ServiceAgentId { service_id });
+        service.agent_ids.extend(agent_ids.clone());
+        service.agent_ids.flush();
+
+        // Copy agent params and agent instances
+        let mut agent_params = LookupMap::new(StorageKey::ServiceAgentParam { service_id });
+        let mut agent_instances = LookupMap::new(StorageKey::ServiceAgentInstance { service_id });
+        let mut all_operators = operators;
+        for agent_id in agent_ids {
+            if let Some(params) = service.agent_params.get(&agent_id) {
+                let mut instances = Vector::new(StorageKey::ServiceAgentIdInstance { service_id, agent_id });
+                for instance in params.instances.iter() {
+                    instances.push(instance.clone());
+                    agent_instances.insert(instance.clone(), agent_id);
+
+                    // Collect operators of agent instances
+                    if let Some(operator) = self.agent_instance_operators.get(instance) {
+                        if !all_operators.contains(operator) {
+                            all_operators.push(operator.clone());
+                        }
+                    }
+                }
+                instances.flush();
+
+                agent_params.insert(agent_id, AgentParams {
+                    num_agent_instances: params.num_agent_instances,
+                    bond: params.bond,
+                    instances
+                });
+            }
+        }
+        agent_params.flush();
+        agent_instances.flush();
+
+        // Copy operators data
+        let mut operators_data = LookupMap::new(StorageKey::ServiceOperatorData { service_id });
+        for operator in all_operators {
+            if let Some(operator_data) = service.operators.get(&operator) {
+                let mut instances = Vector::new(StorageKey::ServiceOperatorInstance { service_id, operator: operator.clone() });
+                instances.extend(operator_data.instances.iter().cloned());
+                instances.flush();
+
+                operators_data.insert(operator, OperatorData {
+                    balance: operator_data.balance,
+                    instances,
+                    whitelisted: operator_data.whitelisted
+                });
+            }
+        }
+        operators_data.flush();
+
+        service.agent_params = agent_params;
+        service.agent_instances = agent_instances;
+        service.operators = operators_data;
+
+        // Move legacy token slashed funds into the token bucket
+        if let Some(token) = service.token.clone() {
+            let mut legacy_slashed_funds: LookupMap<AccountId, u128> = LookupMap::new(StorageKey::TokenBalances);
+            if let Some(amount) = legacy_slashed_funds.remove(&token) {
+                let slashed_funds = self.slashed_funds.entry(TokenKind::FungibleToken(token)).or_insert(0);
+                *slashed_funds = (*slashed_funds).saturating_add(amount);
+                self.slashed_funds.flush();
+            }
+            legacy_slashed_funds.flush();
+        }
+
+        // Add the service to the service indexes
+        let state = service.state.clone();
+        let token = TokenKind::from(service.token.clone());
+        self.services.flush();
+        index_service_state(&mut self.services_by_state, service_id, Some(state.clone()), state);
+        index_service_token(&mut self.services_by_token, service_id, Some(token.clone()), token);
+
+        RegistryEvent::ServiceStorageMigrated { service_id }.emit();
+
+        // Pay for the storage and refund excessive amount
+        let storage = env::storage_usage().saturating_sub(initial_storage_usage);
+        self.refund_deposit_to_account(storage, 0, env::predecessor_account_id(), true);
+    }
+
+    // Gets the upgrade delay taking into account the delay decrease that has already taken effect
+    fn current_upgrade_delay(&self) -> u64 {
+        match &self.pending_upgrade_delay {
+            Some(change) if block_timestamp_secs() >= change.activation_ts => change.delay,
+            _ => self.upgrade_delay
+        }
+    }
+
+    /// Stages the upgrade hash that can only be used after the upgrade delay
     pub fn change_upgrade_hash(&mut self, hash: Vec<u8>) {
-        require!(self.owner_or_self());
+        self.require_role(Role::Upgrader);
 
-        env::log_str(&format!(
-            "ServiceRegistry/{}#{}: update_contract_hash: {}",
-            file!(),
-            line!(),
-            hex::encode(&hash)
-        ));
+        let activation_ts = block_timestamp_secs() + self.current_upgrade_delay();
+
+        RegistryEvent::UpgradeHashUpdated { hash: hex::encode(&hash), activation_ts }.emit();
 
         self.upgrade_hash = hash;
+        self.upgrade_activation_ts = activation_ts;
+    }
+
+    pub fn cancel_upgrade(&mut self) {
+        self.require_role(Role::Upgrader);
+        ensure(!self.upgrade_hash.is_empty(), RegistryError::InvalidUpgradeHash);
+
+        RegistryEvent::UpgradeCancelled { hash: hex::encode(&self.upgrade_hash) }.emit();
+
+        self.upgrade_hash = Vec::new();
+        self.upgrade_activation_ts = 0;
     }
 
-	pub fn upgrade_contract(&self) {
+    /// Changes the upgrade delay, such that an increase takes effect immediately,
+    /// and a decrease takes effect only after the current upgrade delay
+    pub fn change_upgrade_delay(&mut self, delay: u64) {
+        // Check the ownership
+        ensure(self.owner == env::predecessor_account_id(), RegistryError::Unauthorized);
+
+        let current_delay = self.current_upgrade_delay();
+        let activation_ts = if delay >= current_delay {
+            self.upgrade_delay = delay;
+            self.pending_upgrade_delay = None;
+            block_timestamp_secs()
+        } else {
+            let activation_ts = block_timestamp_secs() + current_delay;
+            self.upgrade_delay = current_delay;
+            self.pending_upgrade_delay = Some(UpgradeDelayChange { delay, activation_ts });
+            activation_ts
+        };
+
+        RegistryEvent::UpgradeDelayUpdated { delay, activation_ts }.emit();
+    }
+
+	pub fn upgrade_contract(&mut self) {
+        self.require_role(Role::Upgrader);
+
         // Receive the code directly from the input to avoid the
         // GAS overhead of deserializing parameters
         let code = env::input().expect("Error: No input").to_vec();
@@ -1192,10 +2705,17 @@ impl ServiceRegistry {
         let hash = env::sha256(&code);
 
         // Check if caller is authorized to update the contract code
-        if hash != self.upgrade_hash {
-           env::panic_str("InvalidUpgradeContractHash");
+        if self.upgrade_hash.is_empty() || hash != self.upgrade_hash {
+           RegistryError::InvalidUpgradeHash.panic();
         }
 
+        // Check that the upgrade delay has passed
+        ensure(block_timestamp_secs() >= self.upgrade_activation_ts, RegistryError::UpgradeNotReady);
+
+        // The staged upgrade hash is consumed
+        self.upgrade_hash = Vec::new();
+        self.upgrade_activation_ts = 0;
+
         env::log_str(&format!(
             "ServiceRegistry/{}#{}: : {}",
             file!(),
@@ -1203,36 +2723,140 @@ impl ServiceRegistry {
             hex::encode(&hash)
         ));
 
-        // Deploy the contract on self
+        // Deploy the contract on self and migrate the state within the same batch
         Promise::new(env::current_account_id())
-            .deploy_contract(code);
+            .deploy_contract(code)
+            .function_call("migrate".to_string(), Vec::new(), NearToken::from_yoctonear(0), MIGRATE_GAS);
     }
 
     pub fn set_paused(&mut self, paused: bool) {
-        require!(self.owner_or_self());
+        self.require_role(Role::Pauser);
         self.paused = if paused { true } else { false };
+
+        RegistryEvent::PausedUpdated { paused }.emit();
     }
 
     // TODO: unwrap or else or default panic message is ok?
     pub fn get_service_state(&self, service_id: u32) -> u8 {
-        self.services.get(&service_id).unwrap().state.clone() as u8
+        self.services.get(&service_id).unwrap_or_else(|| RegistryError::ServiceNotFound.panic()).state.clone() as u8
+    }
+
+    pub fn get_service_multisig(&self, service_id: u32) -> Option<AccountId> {
+        self.services.get(&service_id).unwrap_or_else(|| RegistryError::ServiceNotFound.panic()).multisig.clone()
     }
 
-    pub fn get_service_multisig(&self, service_id: u32) -> AccountId {
-        self.services.get(&service_id).unwrap().multisig.clone().unwrap()
+    pub fn get_service_threshold(&self, service_id: u32) -> u32 {
+        self.services.get(&service_id).unwrap_or_else(|| RegistryError::ServiceNotFound.panic()).threshold
+    }
+
+    pub fn get_service_token(&self, service_id: u32) -> Option<AccountId> {
+        self.services.get(&service_id).unwrap_or_else(|| RegistryError::ServiceNotFound.panic()).token.clone()
     }
 
     pub fn get_service_config_hash(&self, service_id: u32) -> [u8; 32] {
-        *self.services.get(&service_id).unwrap().config_hashes.iter().last().unwrap()
+        *self.services.get(&service_id).unwrap_or_else(|| RegistryError::ServiceNotFound.panic()).config_hashes.iter().last().unwrap()
     }
 
     pub fn get_service_previous_config_hashes(&self, service_id: u32) -> Vec<[u8; 32]> {
         // Get config_hashes vector in reverse order without the first element, which is the current config hash
-        self.services.get(&service_id).unwrap().config_hashes.iter().rev().skip(1).cloned().collect()
+        self.services.get(&service_id).unwrap_or_else(|| RegistryError::ServiceNotFound.panic()).config_hashes.iter().rev().skip(1).cloned().collect()
+    }
+
+    pub fn get_service(&self, service_id: u32) -> Option<ServiceView> {
+        let owner_id = self.tokens.owner_by_id.get(&service_id.to_string())?;
+        let service = self.services.get(&service_id)?;
+
+        let agent_params = service.agent_ids
+            .iter()
+            .map(|agent_id| {
+                let params = service.agent_params.get(agent_id).unwrap();
+                AgentParamsView {
+                    agent_id: *agent_id,
+                    num_agent_instances: params.num_agent_instances,
+                    bond: U128::from(params.bond),
+                    instances: params.instances.iter().cloned().collect()
+                }
+            })
+            .collect();
+
+        Some(ServiceView {
+            service_id,
+            owner_id,
+            token: service.token.clone(),
+            security_deposit: U128::from(service.security_deposit),
+            multisig: service.multisig.clone(),
+            config_hash: *service.config_hashes.iter().last().unwrap(),
+            threshold: service.threshold,
+            max_num_agent_instances: service.max_num_agent_instances,
+            num_agent_instances: service.num_agent_instances,
+            state: service.state.clone(),
+            agent_params,
+            operators_check: service.operators_check
+        })
+    }
+
+    /// Returns services for the provided Ids, with none for each non-existent service
+    pub fn get_services(&self, service_ids: Vec<u32>) -> Vec<Option<ServiceView>> {
+        service_ids.into_iter().map(|service_id| self.get_service(service_id)).collect()
+    }
+
+    // Gets service views for the page of provided service Ids
+    fn paginate_services(
+        &self,
+        service_ids: impl Iterator<Item = u32>,
+        from_index: Option<u32>,
+        limit: Option<u32>
+    ) -> Vec<ServiceView> {
+        service_ids
+            .skip(from_index.unwrap_or(0) as usize)
+            .take(limit.unwrap_or(DEFAULT_PAGE_SIZE).min(MAX_PAGE_SIZE) as usize)
+            .filter_map(|service_id| self.get_service(service_id))
+            .collect()
+    }
+
+    pub fn get_services_by_state(
+        &self,
+        state: ServiceState,
+        from_index: Option<u32>,
+        limit: Option<u32>
+    ) -> Vec<ServiceView> {
+        match self.services_by_state.get(&(state as u8)) {
+            Some(service_ids) => self.paginate_services(service_ids.iter().copied(), from_index, limit),
+            None => Vec::new()
+        }
+    }
+
+    pub fn get_services_by_owner(
+        &self,
+        owner_id: AccountId,
+        from_index: Option<u32>,
+        limit: Option<u32>
+    ) -> Vec<ServiceView> {
+        // Service Ids of the owner are tracked by the NFT enumeration
+        match self.tokens.tokens_per_owner.as_ref().and_then(|tokens| tokens.get(&owner_id)) {
+            Some(token_ids) => self.paginate_services(
+                token_ids.iter().filter_map(|token_id| token_id.parse().ok()),
+                from_index,
+                limit
+            ),
+            None => Vec::new()
+        }
+    }
+
+    pub fn get_services_by_token(
+        &self,
+        token: TokenKind,
+        from_index: Option<u32>,
+        limit: Option<u32>
+    ) -> Vec<ServiceView> {
+        match self.services_by_token.get(&token) {
+            Some(service_ids) => self.paginate_services(service_ids.iter().copied(), from_index, limit),
+            None => Vec::new()
+        }
     }
 
     pub fn get_agent_ids(&self, service_id: u32) -> Vec<u32> {
-        self.services.get(&service_id).unwrap().agent_ids.iter().cloned().collect()
+        self.services.get(&service_id).unwrap_or_else(|| RegistryError::ServiceNotFound.panic()).agent_ids.iter().cloned().collect()
     }
 
     pub fn get_service_agent_params_num_instances(&self, service_id: u32) -> Vec<u32> {
@@ -1240,7 +2864,7 @@ impl ServiceRegistry {
 
         // Get the service
         // TODO: unwrap or else or leave just unwrap
-        let service = self.services.get(&service_id).unwrap_or_else(|| env::panic_str("Service not found"));
+        let service = self.services.get(&service_id).unwrap_or_else(|| RegistryError::ServiceNotFound.panic());
         for ai in service.agent_ids.iter() {
             agent_params_num_agent_instances.push(service.agent_params.get(&ai).unwrap().num_agent_instances);
         }
@@ -1251,7 +2875,7 @@ impl ServiceRegistry {
         let mut agent_params_bonds = Vec::new();
 
         // Get the service
-        let service = self.services.get(&service_id).unwrap_or_else(|| env::panic_str("Service not found"));
+        let service = self.services.get(&service_id).unwrap_or_else(|| RegistryError::ServiceNotFound.panic());
         for ai in service.agent_ids.iter() {
             agent_params_bonds.push(service.agent_params.get(&ai).unwrap().bond);
         }
@@ -1262,7 +2886,7 @@ impl ServiceRegistry {
     pub fn get_service_agent_instances(&self, service_id: u32) -> Vec<AccountId> {
         let mut agent_instances = Vec::new();
         // Get the service
-        let service = self.services.get(&service_id).unwrap_or_else(|| env::panic_str("Service not found"));
+        let service = self.services.get(&service_id).unwrap_or_else(|| RegistryError::ServiceNotFound.panic());
         for ai in service.agent_ids.iter() {
             agent_instances.extend(service.agent_params.get(ai).unwrap().instances.iter().cloned());
         }
@@ -1272,25 +2896,37 @@ impl ServiceRegistry {
     pub fn get_instances_for_agent_id(&self, service_id: u32, agent_id: u32) -> Vec<AccountId> {
         // TODO: concatenate
         // Get the service
-        let service = self.services.get(&service_id).unwrap_or_else(|| env::panic_str("Service not found"));
+        let service = self.services.get(&service_id).unwrap_or_else(|| RegistryError::ServiceNotFound.panic());
         // Get agent instances for a specified agent Id
-        service.agent_params.get(&agent_id).unwrap_or_else(|| env::panic_str("Agent not found")).instances.iter().cloned().collect()
+        service.agent_params.get(&agent_id).unwrap_or_else(|| RegistryError::AgentNotFound.panic()).instances.iter().cloned().collect()
+    }
+
+    pub fn is_instance_consent_required(&self) -> bool {
+        self.instance_consent_required
+    }
+
+    pub fn get_instance_consent(&self, agent_instance: AccountId) -> Option<InstanceConsent> {
+        self.instance_consents.get(&agent_instance).cloned()
+    }
+
+    pub fn get_agent_instance_public_key(&self, agent_instance: AccountId) -> Option<PublicKey> {
+        self.agent_instance_keys.get(&agent_instance).cloned()
     }
 
     pub fn get_operator_balance(&self, operator: AccountId, service_id: u32) -> u128 {
         // TODO: concatenate
         // Get the service
-        let service = self.services.get(&service_id).unwrap_or_else(|| env::panic_str("Service not found"));
+        let service = self.services.get(&service_id).unwrap_or_else(|| RegistryError::ServiceNotFound.panic());
         // Get operator balance for a specified service
-        service.operators.get(&operator).unwrap_or_else(|| env::panic_str("Operator not found")).balance
+        service.operators.get(&operator).unwrap_or_else(|| RegistryError::OperatorNotFound.panic()).balance
     }
 
     pub fn get_operator_service_agent_instances(&self, operator: AccountId, service_id: u32) -> Vec<AccountId> {
         // TODO: concatenate
         // Get the service
-        let service = self.services.get(&service_id).unwrap_or_else(|| env::panic_str("Service not found"));
+        let service = self.services.get(&service_id).unwrap_or_else(|| RegistryError::ServiceNotFound.panic());
         // Get agent instances for a specified agent Id
-        service.operators.get(&operator).unwrap_or_else(|| env::panic_str("Operator not found")).instances.iter().cloned().collect()
+        service.operators.get(&operator).unwrap_or_else(|| RegistryError::OperatorNotFound.panic()).instances.iter().cloned().collect()
     }
 
     pub fn is_operator_whitelisted(&self, service_id: u32, operator: AccountId) -> bool {
@@ -1299,22 +2935,45 @@ impl ServiceRegistry {
         let owner_id = self.tokens
             .owner_by_id
             .get(&service_id.to_string())
-            .unwrap_or_else(|| env::panic_str("Service not found"));
+            .unwrap_or_else(|| RegistryError::ServiceNotFound.panic());
 
         // Check the operator whitelisting status, if applied by the service owner
-        let operators_check = self.services.get(&service_id).unwrap().operators_check;
-        if owner_id != operator && operators_check {
-            status = self.services.get(&service_id).unwrap().operators.get(&operator).unwrap().whitelisted;
+        let service = self.services.get(&service_id).unwrap_or_else(|| RegistryError::ServiceNotFound.panic());
+        if owner_id != operator && service.operators_check {
+            // Unknown operators are not whitelisted
+            status = service.operators.get(&operator).is_some_and(|operator_data| operator_data.whitelisted);
         }
 
         status
     }
+    pub fn get_registration_deadline(&self, service_id: u32) -> Option<u64> {
+        self.registration_deadlines.get(&service_id).copied()
+    }
+
+    pub fn get_operators_root(&self, service_id: u32) -> Option<[u8; 32]> {
+        self.operators_roots.get(&service_id).copied()
+    }
+
+    /// Checks the merkle proof of the operator against the service operators root
+    pub fn is_operator_allowed(&self, service_id: u32, operator: AccountId, proof: Vec<[u8; 32]>) -> bool {
+        self.operators_roots
+            .get(&service_id)
+            .is_some_and(|root| verify_operator_proof(root, &operator, &proof))
+    }
+
     pub fn get_registry_balance(&self) -> u128 {
         self.balance
     }
 
-    pub fn get_registry_slashed_funds(&self, token: AccountId) -> u128 {
-        *self.slashed_funds.get(&token).unwrap()
+    pub fn get_registry_slashed_funds(&self, token: TokenKind) -> u128 {
+        self.slashed_funds.get(&token).copied().unwrap_or(0)
+    }
+
+    pub fn get_token_balance(&self, token: AccountId, account_id: AccountId) -> u128 {
+        self.all_token_balances
+            .get(&token)
+            .and_then(|balances| balances.get(&account_id).copied())
+            .unwrap_or(0)
     }
 
     pub fn get_storage_usage(&self) -> u64 {
@@ -1335,7 +2994,7 @@ impl ServiceRegistry {
 //         icon: Option<String>,
 //     ) {
 //         // Only owner can change the metadata
-//         require!(self.owner_or_self());
+//         ensure(self.owner_or_self(), RegistryError::Unauthorized);
 //
 //         name.map(|name| self.name = name);
 //         symbol.map(|symbol| self.symbol = symbol);
@@ -1349,10 +3008,60 @@ impl ServiceRegistry {
         self.tokens.extra_storage_in_bytes_per_token
     }
 
-    /// Return true if the caller is either controller or self
+    /// Return true if the caller is either the registry owner or self
     pub fn owner_or_self(&self) -> bool {
         let caller = env::predecessor_account_id();
-        caller == self.tokens.owner_id || caller == env::current_account_id()
+        caller == self.owner || caller == env::current_account_id()
+    }
+
+    pub fn get_multisig_factories(&self) -> Vec<MultisigFactoryView> {
+        self.multisig_factories
+            .iter()
+            .map(|(multisig_factory, kind)| MultisigFactoryView {
+                multisig_factory: multisig_factory.clone(),
+                kind: *kind,
+                is_default: *multisig_factory == self.multisig_factory
+            })
+            .collect()
+    }
+
+    pub fn get_pending_upgrade(&self) -> Option<PendingUpgrade> {
+        if self.upgrade_hash.is_empty() {
+            return None;
+        }
+
+        Some(PendingUpgrade {
+            hash: hex::encode(&self.upgrade_hash),
+            activation_ts: self.upgrade_activation_ts
+        })
+    }
+
+    pub fn get_upgrade_delay(&self) -> u64 {
+        self.current_upgrade_delay()
+    }
+
+    pub fn get_pending_upgrade_delay(&self) -> Option<UpgradeDelayChange> {
+        self.pending_upgrade_delay.clone().filter(|change| block_timestamp_secs() < change.activation_ts)
+    }
+
+    pub fn get_state_version(&self) -> u32 {
+        self.state_version
+    }
+
+    pub fn get_owner(&self) -> AccountId {
+        self.owner.clone()
+    }
+
+    pub fn get_pending_owner(&self) -> Option<AccountId> {
+        self.pending_owner.clone()
+    }
+
+    pub fn has_role(&self, role: Role, account_id: AccountId) -> bool {
+        self.roles.get(&role).is_some_and(|holders| holders.contains(&account_id))
+    }
+
+    pub fn get_role_holders(&self, role: Role) -> Vec<AccountId> {
+        self.roles.get(&role).map_or_else(Vec::new, |holders| holders.iter().cloned().collect())
     }
 
     pub fn is_paused(&self) -> bool {
@@ -1377,6 +3086,7 @@ impl Default for ServiceRegistry {
     fn default() -> Self {
         Self {
             owner: "".parse().unwrap(),
+            pending_owner: None,
             services: LookupMap::new(StorageKey::Service),
             tokens: NonFungibleToken::new(
                 StorageKey::NonFungibleToken,
@@ -1399,8 +3109,23 @@ impl Default for ServiceRegistry {
             paused: Default::default(),
             multisig_factory: "".parse().unwrap(),
             balance: Default::default(),
-            slashed_funds: LookupMap::new(StorageKey::TokenBalances),
-            upgrade_hash: Vec::new()
+            slashed_funds: LookupMap::new(StorageKey::SlashedFunds),
+            upgrade_hash: Vec::new(),
+            agent_registry: None,
+            services_by_state: LookupMap::new(StorageKey::ServicesByState),
+            services_by_token: LookupMap::new(StorageKey::ServicesByToken),
+            roles: LookupMap::new(StorageKey::Roles),
+            state_version: STATE_VERSION,
+            upgrade_activation_ts: 0,
+            upgrade_delay: DEFAULT_UPGRADE_DELAY,
+            pending_upgrade_delay: None,
+            multisig_factories: IterableMap::new(StorageKey::MultisigFactories),
+            agent_instance_keys: LookupMap::new(StorageKey::AgentInstanceKeys),
+            instance_consent_required: false,
+            instance_consents: LookupMap::new(StorageKey::InstanceConsents),
+            operators_roots: LookupMap::new(StorageKey::OperatorsRoots),
+            registration_deadlines: LookupMap::new(StorageKey::RegistrationDeadlines),
+            pending_token_transfers: LookupMap::new(StorageKey::PendingTokenTransfers)
         }
     }
 }
@@ -1430,7 +3155,7 @@ impl FungibleTokenReceiver for ServiceRegistry {
         if let Some(b) = self
             .all_token_balances
             .get_mut(&token)
-            .unwrap_or_else(|| env::panic_str("Token not registered"))
+            .unwrap_or_else(|| RegistryError::TokenNotRegistered.panic())
             .get_mut(&sender_id)
         {
             // TODO saturated
@@ -1438,10 +3163,10 @@ impl FungibleTokenReceiver for ServiceRegistry {
             *b += amount.0;
         } else {
             // Fail otherwise
-            env::panic_str("Sender not registered");
+            RegistryError::AccountNotRegistered.panic();
         }
 
-        log!("Increased the token amount! {}", amount.0);
+        RegistryEvent::Deposit { account_id: sender_id, token, amount }.emit();
 
         // No tokens will be returned
         PromiseOrValue::Value(U128::from(0))
@@ -0,0 +1,199 @@
+use near_contract_standards::non_fungible_token::metadata::NFTContractMetadata;
+use near_contract_standards::non_fungible_token::NonFungibleToken;
+use near_sdk::borsh::{self, BorshDeserialize};
+use near_sdk::store::{IterableMap, IterableSet, LookupMap};
+use near_sdk::{env, near, AccountId};
+
+use crate::{
+    MultisigKind, Role, Service, ServiceRegistry, ServiceRegistryExt, StorageKey, TokenKind, DEFAULT_UPGRADE_DELAY,
+    STATE_VERSION
+};
+
+// Legacy native token key of slashed funds
+const LEGACY_NATIVE_TOKEN: &str = "near.near";
+
+// Registry state layout before the state versioning was introduced
+#[near(serializers=[borsh])]
+pub struct ServiceRegistryV0 {
+    owner: AccountId,
+    services: LookupMap<u32, Service>,
+    tokens: NonFungibleToken,
+    metadata: Option<NFTContractMetadata>,
+    all_token_balances: LookupMap<AccountId, LookupMap<AccountId, u128>>,
+    agent_instance_operators: LookupMap<AccountId, AccountId>,
+    paused: bool,
+    multisig_factory: AccountId,
+    balance: u128,
+    slashed_funds: LookupMap<AccountId, u128>,
+    upgrade_hash: Vec<u8>
+}
+
+// Registry state head shared by all the versioned layouts, starting from version 1.
+// Fields of each later version are appended after the head in the order of versions
+#[near(serializers=[borsh])]
+pub struct ServiceRegistryHead {
+    owner: AccountId,
+    pending_owner: Option<AccountId>,
+    services: LookupMap<u32, Service>,
+    tokens: NonFungibleToken,
+    metadata: Option<NFTContractMetadata>,
+    all_token_balances: LookupMap<AccountId, LookupMap<AccountId, u128>>,
+    agent_instance_operators: LookupMap<AccountId, AccountId>,
+    paused: bool,
+    multisig_factory: AccountId,
+    balance: u128,
+    slashed_funds: LookupMap<TokenKind, u128>,
+    upgrade_hash: Vec<u8>,
+    agent_registry: Option<AccountId>,
+    services_by_state: LookupMap<u8, IterableSet<u32>>,
+    services_by_token: LookupMap<TokenKind, IterableSet<u32>>,
+    roles: LookupMap<Role, IterableSet<AccountId>>,
+    state_version: u32
+}
+
+impl From<ServiceRegistryV0> for ServiceRegistryHead {
+    fn from(old: ServiceRegistryV0) -> Self {
+        // Move native slashed funds into the native bucket
+        // Token slashed funds are moved for each service by migrate_service_storage()
+        let mut old_slashed_funds = old.slashed_funds;
+        let mut slashed_funds = LookupMap::new(StorageKey::SlashedFunds);
+        if let Some(amount) = old_slashed_funds.remove(&LEGACY_NATIVE_TOKEN.parse::<AccountId>().unwrap()) {
+            slashed_funds.insert(TokenKind::Native, amount);
+        }
+        old_slashed_funds.flush();
+        slashed_funds.flush();
+
+        Self {
+            owner: old.owner,
+            pending_owner: None,
+            services: old.services,
+            tokens: old.tokens,
+            metadata: old.metadata,
+            all_token_balances: old.all_token_balances,
+            agent_instance_operators: old.agent_instance_operators,
+            paused: old.paused,
+            multisig_factory: old.multisig_factory,
+            balance: old.balance,
+            slashed_funds,
+            upgrade_hash: old.upgrade_hash,
+            agent_registry: None,
+            services_by_state: LookupMap::new(StorageKey::ServicesByState),
+            services_by_token: LookupMap::new(StorageKey::ServicesByToken),
+            roles: LookupMap::new(StorageKey::Roles),
+            state_version: 0
+        }
+    }
+}
+
+// Reads the field appended to the state in the provided version, or creates it for the state of an older version
+fn read_field<T: BorshDeserialize>(
+    reader: &mut &[u8],
+    state_version: u32,
+    since_version: u32,
+    create: impl FnOnce() -> T
+) -> Option<T> {
+    if state_version >= since_version {
+        T::deserialize(reader).ok()
+    } else {
+        Some(create())
+    }
+}
+
+// Reads the fields appended after the state head according to its version and builds the current state
+fn read_state(head: ServiceRegistryHead, reader: &mut &[u8]) -> Option<ServiceRegistry> {
+    let version = head.state_version;
+
+    // Version 2 introduced the upgrade timelock
+    let upgrade_activation_ts = read_field(reader, version, 2, || 0)?;
+    let upgrade_delay = read_field(reader, version, 2, || DEFAULT_UPGRADE_DELAY)?;
+    let pending_upgrade_delay = read_field(reader, version, 2, || None)?;
+    // Version 3 introduced multiple multisig factories, the existing one stays the default with the multisig2 interface
+    let multisig_factories = read_field(reader, version, 3, || {
+        let mut multisig_factories = IterableMap::new(StorageKey::MultisigFactories);
+        multisig_factories.insert(head.multisig_factory.clone(), MultisigKind::Multisig2);
+        multisig_factories.flush();
+        multisig_factories
+    })?;
+    // Version 4 introduced agent instance public keys
+    let agent_instance_keys = read_field(reader, version, 4, || LookupMap::new(StorageKey::AgentInstanceKeys))?;
+    // Version 5 introduced agent instance consents
+    let instance_consent_required = read_field(reader, version, 5, || false)?;
+    let instance_consents = read_field(reader, version, 5, || LookupMap::new(StorageKey::InstanceConsents))?;
+    // Version 6 introduced operators merkle roots
+    let operators_roots = read_field(reader, version, 6, || LookupMap::new(StorageKey::OperatorsRoots))?;
+    // Version 7 introduced registration deadlines
+    let registration_deadlines = read_field(reader, version, 7, || LookupMap::new(StorageKey::RegistrationDeadlines))?;
+    // Version 8 introduced pending token transfers
+    let pending_token_transfers = read_field(reader, version, 8, || LookupMap::new(StorageKey::PendingTokenTransfers))?;
+
+    // The whole state must be consumed by its layout
+    if !reader.is_empty() {
+        return None;
+    }
+
+    Some(ServiceRegistry {
+        owner: head.owner,
+        pending_owner: head.pending_owner,
+        services: head.services,
+        tokens: head.tokens,
+        metadata: head.metadata,
+        all_token_balances: head.all_token_balances,
+        agent_instance_operators: head.agent_instance_operators,
+        paused: head.paused,
+        multisig_factory: head.multisig_factory,
+        balance: head.balance,
+        slashed_funds: head.slashed_funds,
+        // The upgrade hash set before the upgrade timelock is discarded, such that each upgrade is staged with the delay
+        upgrade_hash: if version >= 2 { head.upgrade_hash } else { Vec::new() },
+        agent_registry: head.agent_registry,
+        services_by_state: head.services_by_state,
+        services_by_token: head.services_by_token,
+        roles: head.roles,
+        state_version: STATE_VERSION,
+        upgrade_activation_ts,
+        upgrade_delay,
+        pending_upgrade_delay,
+        multisig_factories,
+        agent_instance_keys,
+        instance_consent_required,
+        instance_consents,
+        operators_roots,
+        registration_deadlines,
+        pending_token_transfers
+    })
+}
+
+#[near]
+impl ServiceRegistry {
+    /// Migrates the registry state into the current layout, called on self right after the code is deployed
+    #[private]
+    #[init(ignore_state)]
+    pub fn migrate() -> Self {
+        let state = env::storage_read(b"STATE").unwrap_or_else(|| env::panic_str("No state to migrate"));
+
+        // Decode the versioned state head and dispatch on its version
+        let mut reader = state.as_slice();
+        if let Ok(head) = ServiceRegistryHead::deserialize(&mut reader) {
+            let from_version = head.state_version;
+            if (1..=STATE_VERSION).contains(&from_version) {
+                if let Some(registry) = read_state(head, &mut reader) {
+                    if from_version < STATE_VERSION {
+                        env::log_str(&format!("Migrated the registry state from version {} to version {}",
+                            from_version, STATE_VERSION));
+                    }
+                    return registry;
+                }
+            }
+        }
+
+        // Otherwise the state is in the layout before the state versioning was introduced
+        let old = borsh::from_slice::<ServiceRegistryV0>(&state)
+            .unwrap_or_else(|_| env::panic_str("Unknown state layout"));
+        let registry = read_state(old.into(), &mut [].as_slice())
+            .unwrap_or_else(|| env::panic_str("Unknown state layout"));
+
+        env::log_str(&format!("Migrated the registry state from version {} to version {}", 0, STATE_VERSION));
+
+        registry
+    }
+}
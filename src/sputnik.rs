@@ -0,0 +1,196 @@
+use std::collections::HashMap;
+
+use near_sdk::json_types::{Base64VecU8, U128, U64};
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::serde_json;
+use near_sdk::{ext_contract, AccountId, Promise};
+
+// Sputnik DAO v2 types, only the parts needed to create the DAO and to check its policy
+
+// Name of the DAO role that holds agent instances
+const COUNCIL_ROLE: &str = "council";
+// Default Sputnik DAO v2 policy values
+const PROPOSAL_BOND: u128 = 1_000_000_000_000_000_000_000_000;
+const PROPOSAL_PERIOD: u64 = 604_800_000_000_000;
+const BOUNTY_BOND: u128 = 1_000_000_000_000_000_000_000_000;
+const BOUNTY_FORGIVENESS_PERIOD: u64 = 86_400_000_000_000;
+
+// SputnikDaoFactory interface
+#[ext_contract(sputnik_dao_factory)]
+trait SputnikDaoFactory {
+    #[payable]
+    fn create(&mut self, name: AccountId, args: Base64VecU8) -> Promise;
+}
+
+// SputnikDao interface
+#[ext_contract(sputnik_dao)]
+trait SputnikDao {
+    fn get_policy(&self) -> Policy;
+}
+
+#[derive(Serialize, Deserialize, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub enum RoleKind {
+    Everyone,
+    Member(U128),
+    Group(Vec<AccountId>)
+}
+
+#[derive(Serialize, Deserialize, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub enum WeightKind {
+    TokenWeight,
+    RoleWeight
+}
+
+#[derive(Serialize, Deserialize, PartialEq)]
+#[serde(crate = "near_sdk::serde", untagged)]
+pub enum WeightOrRatio {
+    Weight(U128),
+    Ratio(u64, u64)
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct VotePolicy {
+    pub weight_kind: WeightKind,
+    pub quorum: U128,
+    pub threshold: WeightOrRatio
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct RolePermission {
+    pub name: String,
+    pub kind: RoleKind,
+    pub permissions: Vec<String>,
+    pub vote_policy: HashMap<String, VotePolicy>
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct Policy {
+    pub roles: Vec<RolePermission>,
+    pub default_vote_policy: VotePolicy,
+    pub proposal_bond: U128,
+    pub proposal_period: U64,
+    pub bounty_bond: U128,
+    pub bounty_forgiveness_period: U64
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+struct Config {
+    name: String,
+    purpose: String,
+    metadata: Base64VecU8
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+struct CreateArgs {
+    config: Config,
+    policy: Policy
+}
+
+// Policy where agent instances are the only council members and any threshold of them approves proposals
+fn service_policy(members: Vec<AccountId>, threshold: u32) -> Policy {
+    Policy {
+        roles: vec![RolePermission {
+            name: COUNCIL_ROLE.to_string(),
+            kind: RoleKind::Group(members),
+            permissions: vec!["*:*".to_string()],
+            vote_policy: HashMap::new()
+        }],
+        default_vote_policy: VotePolicy {
+            weight_kind: WeightKind::RoleWeight,
+            quorum: U128::from(0),
+            threshold: WeightOrRatio::Weight(U128::from(threshold as u128))
+        },
+        proposal_bond: U128::from(PROPOSAL_BOND),
+        proposal_period: U64::from(PROPOSAL_PERIOD),
+        bounty_bond: U128::from(BOUNTY_BOND),
+        bounty_forgiveness_period: U64::from(BOUNTY_FORGIVENESS_PERIOD)
+    }
+}
+
+/// Returns the Sputnik DAO factory arguments to create the DAO of service agent instances
+pub fn create_args(name: &AccountId, service_id: u32, members: Vec<AccountId>, threshold: u32) -> Base64VecU8 {
+    let args = CreateArgs {
+        config: Config {
+            name: name.to_string(),
+            purpose: format!("Service {} multisig", service_id),
+            metadata: Base64VecU8::from(Vec::new())
+        },
+        policy: service_policy(members, threshold)
+    };
+    Base64VecU8::from(serde_json::to_vec(&args).unwrap())
+}
+
+// Checks if the permission allows voting on proposals
+fn is_vote_permission(permission: &str) -> bool {
+    let action = permission.split(':').nth(1).unwrap_or(permission);
+    matches!(action, "*" | "VoteApprove" | "VoteReject" | "VoteRemove")
+}
+
+/// Checks that only agent instances are able to vote and that the default vote threshold matches the service one
+pub fn policy_matches(policy: &Policy, members: &[AccountId], threshold: u32) -> bool {
+    let default_policy = &policy.default_vote_policy;
+    if default_policy.weight_kind != WeightKind::RoleWeight
+        || default_policy.threshold != WeightOrRatio::Weight(U128::from(threshold as u128)) {
+        return false;
+    }
+
+    let mut council_found = false;
+    for role in policy.roles.iter() {
+        let votes = role.permissions.iter().any(|permission| is_vote_permission(permission));
+        match &role.kind {
+            RoleKind::Group(group) if role.name == COUNCIL_ROLE => {
+                // Council members must be exactly the agent instances
+                let mut sorted_group = group.clone();
+                sorted_group.sort();
+                let mut sorted_members = members.to_vec();
+                sorted_members.sort();
+                if sorted_group != sorted_members || !role.vote_policy.is_empty() {
+                    return false;
+                }
+                council_found = true;
+            }
+            // Any other role must not be able to vote
+            _ => {
+                if votes {
+                    return false;
+                }
+            }
+        }
+    }
+
+    council_found
+}
+
+/// Checks if the account is the DAO council member
+pub fn is_council_member(policy: &Policy, account_id: &AccountId) -> bool {
+    policy.roles.iter().any(|role| role.name == COUNCIL_ROLE
+        && matches!(&role.kind, RoleKind::Group(group) if group.contains(account_id)))
+}
+
+/// Returns the DAO council members
+pub fn council_members(policy: &Policy) -> Vec<AccountId> {
+    policy.roles
+        .iter()
+        .filter(|role| role.name == COUNCIL_ROLE)
+        .flat_map(|role| match &role.kind {
+            RoleKind::Group(group) => group.clone(),
+            _ => Vec::new()
+        })
+        .collect()
+}
+
+/// Returns the default vote threshold as the number of council members, or zero if it is not set by the role weight
+pub fn vote_threshold(policy: &Policy) -> u32 {
+    let default_policy = &policy.default_vote_policy;
+    match (&default_policy.weight_kind, &default_policy.threshold) {
+        (WeightKind::RoleWeight, WeightOrRatio::Weight(weight)) => u32::try_from(weight.0).unwrap_or(u32::MAX),
+        _ => 0
+    }
+}
@@ -1,5 +1,7 @@
-import {Worker, NEAR, NearAccount} from "near-workspaces";
+import {Worker, NEAR, NearAccount, KeyPair, TransactionResult} from "near-workspaces";
 import anyTest, {TestFn} from "ava";
+import * as fs from "fs";
+import * as crypto from "crypto";
 
 const serviceId = 1;
 const configHash = Array(32).fill(5);
@@ -33,6 +35,25 @@ const defaultServiceMetadata = {
 }
 
 
+// Parses the events emitted in the transaction logs
+function parseEvents(result: TransactionResult): any[] {
+    return result.logs
+        .filter(log => log.startsWith("EVENT_JSON:"))
+        .map(log => JSON.parse(log.slice("EVENT_JSON:".length)));
+}
+
+// Fast forwards blocks until the block time reaches the provided time in seconds
+async function fastForwardTo(worker: Worker, ts: number) {
+    for (;;) {
+        const block = await worker.provider.block({finality: "final"});
+        const now = Math.floor(Number(block.header.timestamp) / 1e9);
+        if (now >= ts) {
+            return;
+        }
+        await worker.provider.fastForward(ts - now);
+    }
+}
+
 const test = anyTest as TestFn<{
     worker: Worker;
     accounts: Record<string, NearAccount>;
@@ -130,6 +151,43 @@ test("Create service and check its state", async t => {
     console.log(metadata);
 });
 
+test("Create service and check the emitted event", async t => {
+    const {root, contract, deployer} = t.context.accounts;
+
+    // Initialize the contract
+    await root.call(contract, "new", {
+        multisig_factory: deployer,
+        metadata: defaultContractMetadata
+    });
+
+    // Create service
+    const attachedDeposit = "5 N";
+    const tx = await root.callRaw(contract, "create", {
+        service_owner: deployer,
+        metadata: defaultServiceMetadata,
+        config_hash: configHash,
+        agent_ids: agentIds,
+        agent_num_instances: agentNumInstances,
+        agent_bonds: agentBonds,
+        threshold
+    }, {attachedDeposit});
+
+    // Get the service registry events
+    const events = parseEvents(tx).filter(event => event.standard === "olas_service_registry");
+    t.is(events.length, 1);
+
+    // Check the create service event
+    const event = events[0];
+    t.is(event.version, "1.0.0");
+    t.is(event.event, "create_service");
+    t.is(event.data.service_id, serviceId);
+    t.is(event.data.owner_id, deployer.accountId);
+    t.is(event.data.config_hash, Buffer.from(configHash).toString("hex"));
+    t.deepEqual(event.data.agent_ids, agentIds);
+    t.deepEqual(event.data.agent_bonds, agentBonds.map(String));
+    t.is(event.data.threshold, threshold);
+});
+
 test("Update service with the same setup and check its state", async t => {
     const {root, contract, deployer} = t.context.accounts;
 
@@ -698,4 +756,1881 @@ test("Unbond after terminating the service with a token deposit", async t => {
     // Check contract balance after registration
     balance = await contract.view("get_registry_balance", {});
     t.is(balance, 0);
-});
\ No newline at end of file
+});
+
+test("Pause the registry and check that service entry points are blocked", async t => {
+    const {root, contract, deployer, operator, agentInstance} = t.context.accounts;
+
+    // Initialize the contract
+    await root.call(contract, "new", {
+        multisig_factory: deployer,
+        metadata: defaultContractMetadata
+    });
+
+    // Create service
+    const attachedDeposit = "5 N";
+    await root.call(contract, "create", {
+        service_owner: deployer,
+        metadata: defaultServiceMetadata,
+        config_hash: configHash,
+        agent_ids: agentIds,
+        agent_num_instances: agentNumInstances,
+        agent_bonds: agentBonds,
+        threshold
+    }, {attachedDeposit});
+
+    // Create the second service that is left in the PreRegistration state
+    await root.call(contract, "create", {
+        service_owner: deployer,
+        metadata: defaultServiceMetadata,
+        config_hash: configHash2,
+        agent_ids: agentIds,
+        agent_num_instances: agentNumInstances,
+        agent_bonds: agentBonds,
+        threshold
+    }, {attachedDeposit});
+
+    // Activate service agent registration
+    await deployer.call(contract, "activate_registration", {
+        service_id: serviceId,
+    }, {attachedDeposit});
+
+    // Pause the registry
+    await contract.call(contract, "set_paused", {paused: true});
+    let result = await contract.view("is_paused", {});
+    t.is(result, true);
+
+    // Try to create another service
+    await t.throwsAsync(root.call(contract, "create", {
+        service_owner: deployer,
+        metadata: defaultServiceMetadata,
+        config_hash: configHash,
+        agent_ids: agentIds,
+        agent_num_instances: agentNumInstances,
+        agent_bonds: agentBonds,
+        threshold
+    }, {attachedDeposit}), {message: /E002: Registry is paused/});
+
+    // Try to update the service
+    await t.throwsAsync(deployer.call(contract, "update", {
+        service_id: serviceId,
+        config_hash: configHash2,
+        agent_ids: agentIds,
+        agent_num_instances: agentNumInstances,
+        agent_bonds: agentBonds,
+        threshold
+    }, {attachedDeposit}), {message: /E002: Registry is paused/});
+
+    // Try to register agent instances
+    await t.throwsAsync(operator.call(contract, "register_agents", {
+        service_id: serviceId,
+        agent_instances: [agentInstance],
+        agent_ids: agentIds
+    }, {attachedDeposit}), {message: /E002: Registry is paused/});
+
+    // Try to activate the second service agent registration
+    await t.throwsAsync(deployer.call(contract, "activate_registration", {
+        service_id: serviceId + 1,
+    }, {attachedDeposit}), {message: /E002: Registry is paused/});
+
+    // Check that the service is still in the ActiveRegistration state
+    result = await contract.view("get_service_state", {service_id: serviceId});
+    t.is(result, 2);
+
+    // Unpause the registry and register agent instances
+    await contract.call(contract, "set_paused", {paused: false});
+    await operator.call(contract, "register_agents", {
+        service_id: serviceId,
+        agent_instances: [agentInstance],
+        agent_ids: agentIds
+    }, {attachedDeposit});
+
+    // Check that the service is in the FinishedRegistration state
+    result = await contract.view("get_service_state", {service_id: serviceId});
+    t.is(result, 3);
+
+    // Pause the registry again and try to deploy the service
+    await contract.call(contract, "set_paused", {paused: true});
+    await t.throwsAsync(deployer.call(contract, "deploy", {
+        service_id: serviceId,
+        name_multisig: "multisig"
+    }, {attachedDeposit, gas: "300 Tgas"}), {message: /E002: Registry is paused/});
+
+    // Check that the services are still in the FinishedRegistration and PreRegistration states
+    result = await contract.view("get_service_state", {service_id: serviceId});
+    t.is(result, 3);
+    result = await contract.view("get_service_state", {service_id: serviceId + 1});
+    t.is(result, 1);
+});
+
+test("Terminate and unbond when the registry is paused", async t => {
+    const {root, contract, deployer, operator, agentInstance} = t.context.accounts;
+
+    // Initialize the contract
+    await root.call(contract, "new", {
+        multisig_factory: deployer,
+        metadata: defaultContractMetadata
+    });
+
+    // Create service
+    const attachedDeposit = "5 N";
+    await root.call(contract, "create", {
+        service_owner: deployer,
+        metadata: defaultServiceMetadata,
+        config_hash: configHash,
+        agent_ids: agentIds,
+        agent_num_instances: agentNumInstances,
+        agent_bonds: agentBonds,
+        threshold
+    }, {attachedDeposit});
+
+    // Activate service agent registration
+    await deployer.call(contract, "activate_registration", {
+        service_id: serviceId,
+    }, {attachedDeposit});
+
+    // Operator to register agent instance
+    await operator.call(contract, "register_agents", {
+        service_id: serviceId,
+        agent_instances: [agentInstance],
+        agent_ids: agentIds
+    }, {attachedDeposit});
+
+    // Pause the registry
+    await contract.call(contract, "set_paused", {paused: true});
+
+    // Terminate service
+    await deployer.call(contract, "terminate", {
+        service_id: serviceId,
+    }, {attachedDeposit});
+
+    // Check that the service is in the TerminatedBonded state
+    let result = await contract.view("get_service_state", {service_id: serviceId});
+    t.is(result, 5);
+
+    // Unbond operator
+    await operator.call(contract, "unbond", {
+        service_id: serviceId,
+    }, {attachedDeposit});
+
+    // Check that the service is in the PreRegistration state
+    result = await contract.view("get_service_state", {service_id: serviceId});
+    t.is(result, 1);
+
+    // Check the registry balance
+    const balance = await contract.view("get_registry_balance", {});
+    t.is(balance, 0);
+});
+
+test("Create and register two services and check that their data does not collide", async t => {
+    const {root, contract, deployer, operator, agentInstance, agentInstance2} = t.context.accounts;
+
+    // Initialize the contract
+    await root.call(contract, "new", {
+        multisig_factory: deployer,
+        metadata: defaultContractMetadata
+    });
+
+    // Create two services with the same agent ids
+    const attachedDeposit = "5 N";
+    await root.call(contract, "create", {
+        service_owner: deployer,
+        metadata: defaultServiceMetadata,
+        config_hash: configHash,
+        agent_ids: [1, 2],
+        agent_num_instances: [1, 1],
+        agent_bonds: [1000, 1000],
+        threshold: 2
+    }, {attachedDeposit});
+    await root.call(contract, "create", {
+        service_owner: deployer,
+        metadata: defaultServiceMetadata,
+        config_hash: configHash2,
+        agent_ids: [1],
+        agent_num_instances: [1],
+        agent_bonds: [2000],
+        threshold: 1
+    }, {attachedDeposit});
+
+    // Check config hashes
+    let result = await contract.view("get_service_config_hash", {service_id: 1});
+    t.deepEqual(result, configHash);
+    result = await contract.view("get_service_config_hash", {service_id: 2});
+    t.deepEqual(result, configHash2);
+
+    // Check agent ids and bonds
+    result = await contract.view("get_agent_ids", {service_id: 1});
+    t.deepEqual(result, [1, 2]);
+    result = await contract.view("get_agent_ids", {service_id: 2});
+    t.deepEqual(result, [1]);
+    result = await contract.view("get_service_agent_params_bonds", {service_id: 1});
+    t.deepEqual(result, [1000, 1000]);
+    result = await contract.view("get_service_agent_params_bonds", {service_id: 2});
+    t.deepEqual(result, [2000]);
+
+    // Activate both services agent registration
+    await deployer.call(contract, "activate_registration", {
+        service_id: 1,
+    }, {attachedDeposit});
+    await deployer.call(contract, "activate_registration", {
+        service_id: 2,
+    }, {attachedDeposit});
+
+    // Register agent instances for the same agent id in different services
+    await operator.call(contract, "register_agents", {
+        service_id: 1,
+        agent_instances: [agentInstance],
+        agent_ids: [1]
+    }, {attachedDeposit});
+    await operator.call(contract, "register_agents", {
+        service_id: 2,
+        agent_instances: [agentInstance2],
+        agent_ids: [1]
+    }, {attachedDeposit});
+
+    // Check that each service only sees its own agent instances
+    result = await contract.view("get_service_agent_instances", {service_id: 1});
+    t.deepEqual(result, [agentInstance.accountId]);
+    result = await contract.view("get_service_agent_instances", {service_id: 2});
+    t.deepEqual(result, [agentInstance2.accountId]);
+    result = await contract.view("get_operator_service_agent_instances", {operator: operator, service_id: 1});
+    t.deepEqual(result, [agentInstance.accountId]);
+    result = await contract.view("get_operator_service_agent_instances", {operator: operator, service_id: 2});
+    t.deepEqual(result, [agentInstance2.accountId]);
+
+    // Check operator balances
+    result = await contract.view("get_operator_balance", {operator: operator, service_id: 1});
+    t.is(result, 1000);
+    result = await contract.view("get_operator_balance", {operator: operator, service_id: 2});
+    t.is(result, 2000);
+
+    // Check service states: the first one still has a free slot
+    result = await contract.view("get_service_state", {service_id: 1});
+    t.is(result, 2);
+    result = await contract.view("get_service_state", {service_id: 2});
+    t.is(result, 3);
+
+    // Migrating already namespaced storage must keep the data intact
+    await root.call(contract, "migrate_service_storage", {
+        service_id: 1,
+        operators: []
+    }, {attachedDeposit});
+    result = await contract.view("get_service_agent_instances", {service_id: 1});
+    t.deepEqual(result, [agentInstance.accountId]);
+    result = await contract.view("get_operator_balance", {operator: operator, service_id: 1});
+    t.is(result, 1000);
+});
+
+test("Migrate the service storage from the baseline layout after the upgrade", async t => {
+    const {root, deployer, operator, agentInstance, agentInstance2} = t.context.accounts;
+    const operator2 = await root.createSubAccount("operator2", {initialBalance: NEAR.parse("10 N").toJSON()});
+
+    // Deploy the registry contract of the baseline version with shared storage prefixes
+    const contract = await root.devDeploy(
+        "artifacts/registries_near_baseline.wasm",
+        {initialBalance: NEAR.parse("20 N").toJSON()},
+    );
+    await root.call(contract, "new", {
+        multisig_factory: deployer,
+        metadata: defaultContractMetadata
+    });
+
+    // Create service, whitelist the second operator without agent instances and register the agent instance
+    const attachedDeposit = "5 N";
+    await root.call(contract, "create", {
+        service_owner: deployer,
+        metadata: defaultServiceMetadata,
+        config_hash: configHash,
+        agent_ids: [1, 2],
+        agent_num_instances: [1, 1],
+        agent_bonds: [1000, 2000],
+        threshold: 2
+    }, {attachedDeposit});
+    await deployer.call(contract, "set_operators_statuses", {
+        service_id: serviceId,
+        operators: [operator2],
+        statuses: [true],
+        set_check: false
+    }, {attachedDeposit});
+    await deployer.call(contract, "activate_registration", {
+        service_id: serviceId,
+    }, {attachedDeposit});
+    await operator.call(contract, "register_agents", {
+        service_id: serviceId,
+        agent_instances: [agentInstance],
+        agent_ids: [1]
+    }, {attachedDeposit});
+
+    // Upgrade the contract with the baseline upgrade flow and migrate the registry state
+    const code = fs.readFileSync("target/wasm32-unknown-unknown/release/registries_near.wasm");
+    const hash = Array.from(crypto.createHash("sha256").update(code).digest());
+    await root.call(contract, "change_upgrade_hash", {hash});
+    await root.call(contract, "upgrade_contract", code, {gas: "300 Tgas"});
+    await contract.call(contract, "migrate", {}, {gas: "300 Tgas"});
+
+    // Move the service collections under per-service prefixes
+    await root.call(contract, "migrate_service_storage", {
+        service_id: serviceId,
+        operators: [operator2]
+    }, {attachedDeposit});
+
+    // Check operator balances and agent instances after the migration
+    let result: any = await contract.view("get_operator_balance", {operator: operator, service_id: serviceId});
+    t.is(result, 1000);
+    result = await contract.view("get_operator_service_agent_instances", {operator: operator, service_id: serviceId});
+    t.deepEqual(result, [agentInstance.accountId]);
+    result = await contract.view("get_operator_balance", {operator: operator2, service_id: serviceId});
+    t.is(result, 0);
+    result = await contract.view("get_service_agent_instances", {service_id: serviceId});
+    t.deepEqual(result, [agentInstance.accountId]);
+    result = await contract.view("get_instances_for_agent_id", {service_id: serviceId, agent_id: 1});
+    t.deepEqual(result, [agentInstance.accountId]);
+    result = await contract.view("get_services_by_state", {state: "ActiveRegistration"});
+    t.deepEqual(result.map((s: any) => s.service_id), [serviceId]);
+    result = await contract.view("get_registry_balance", {});
+    t.is(result, 3000);
+
+    // The migrated service keeps registering agent instances
+    await operator2.call(contract, "register_agents", {
+        service_id: serviceId,
+        agent_instances: [agentInstance2],
+        agent_ids: [2]
+    }, {attachedDeposit});
+    result = await contract.view("get_service_agent_instances", {service_id: serviceId});
+    t.deepEqual(result, [agentInstance.accountId, agentInstance2.accountId]);
+    result = await contract.view("get_operator_balance", {operator: operator2, service_id: serviceId});
+    t.is(result, 2000);
+    result = await contract.view("get_service_state", {service_id: serviceId});
+    t.is(result, 3);
+});
+
+test("Refund the deposit when the multisig creation fails and deploy the service afterwards", async t => {
+    const {root, contract, deployer, operator, agentInstance} = t.context.accounts;
+
+    // Deploy and initialize the test multisig factory
+    const factory = await root.devDeploy(
+        "target/wasm32-unknown-unknown/release/test_multisig_factory.wasm",
+        {initialBalance: NEAR.parse("10 N").toJSON()},
+    );
+    await root.call(factory, "new", {});
+
+    // Initialize the contract
+    await root.call(contract, "new", {
+        multisig_factory: factory,
+        metadata: defaultContractMetadata
+    });
+
+    // Create service, activate its registration and register agent instances
+    const attachedDeposit = "5 N";
+    await root.call(contract, "create", {
+        service_owner: deployer,
+        metadata: defaultServiceMetadata,
+        config_hash: configHash,
+        agent_ids: agentIds,
+        agent_num_instances: agentNumInstances,
+        agent_bonds: agentBonds,
+        threshold
+    }, {attachedDeposit});
+    await deployer.call(contract, "activate_registration", {
+        service_id: serviceId,
+    }, {attachedDeposit});
+    await operator.call(contract, "register_agents", {
+        service_id: serviceId,
+        agent_instances: [agentInstance],
+        agent_ids: agentIds
+    }, {attachedDeposit});
+
+    // Set the factory to fail and try to deploy the service
+    await root.call(factory, "set_fail", {fail: true});
+    const balanceBefore = await deployer.balance();
+    const outcome = await deployer.callRaw(contract, "deploy", {
+        service_id: serviceId,
+        name_multisig: "multisig"
+    }, {attachedDeposit, gas: "300 Tgas"});
+
+    // Check the failure event
+    const event = parseEvents(outcome).find(e => e.event === "create_multisig_failed");
+    t.truthy(event);
+    t.is(event.data.owner_id, deployer.accountId);
+    t.is(event.data.multisig, "multisig." + factory.accountId);
+
+    // The service is still in the FinishedRegistration state and the deposit is refunded
+    let result = await contract.view("get_service_state", {service_id: serviceId});
+    t.is(result, 3);
+    const balanceAfter = await deployer.balance();
+    t.true(balanceBefore.available.sub(balanceAfter.available).lt(NEAR.parse("0.1 N")));
+
+    // Deploy the service with the working factory
+    await root.call(factory, "set_fail", {fail: false});
+    await deployer.call(contract, "deploy", {
+        service_id: serviceId,
+        name_multisig: "multisig"
+    }, {attachedDeposit, gas: "300 Tgas"});
+
+    // The service records the full account id of the created multisig
+    result = await contract.view("get_service_state", {service_id: serviceId});
+    t.is(result, 4);
+    result = await contract.view("get_service_multisig", {service_id: serviceId});
+    t.is(result, "multisig." + factory.accountId);
+    t.true(await root.getAccount("multisig." + factory.accountId).exists());
+});
+
+test("Re-credit the token balance when the withdrawal transfer fails", async t => {
+    const {root, contract, token, deployer, operator} = t.context.accounts;
+
+    // Initialize the contract
+    await root.call(contract, "new", {
+        multisig_factory: deployer,
+        metadata: defaultContractMetadata
+    });
+
+    // Create service with the token to register it
+    const attachedDeposit = "5 N";
+    await root.call(contract, "create", {
+        service_owner: deployer,
+        metadata: defaultServiceMetadata,
+        token: token.accountId,
+        config_hash: configHash,
+        agent_ids: agentIds,
+        agent_num_instances: agentNumInstances,
+        agent_bonds: agentBonds,
+        threshold
+    }, {attachedDeposit, gas: "300 Tgas"});
+
+    // Register operator and send tokens to the registry contract
+    await operator.call(contract, "storage_deposit", {
+        token: token.accountId
+    }, {attachedDeposit});
+    await operator.call(token, "ft_transfer_call", {
+        receiver_id: contract.accountId,
+        amount: agentBonds[0].toString(),
+        msg: ""
+    }, {attachedDeposit: "1", gas: "300 Tgas"});
+
+    // Unregister the operator from the token contract
+    const operatorBalance: string = await token.view("ft_balance_of", {account_id: operator.accountId});
+    await operator.call(token, "ft_transfer", {
+        receiver_id: root.accountId,
+        amount: operatorBalance
+    }, {attachedDeposit: "1"});
+    await operator.call(token, "storage_unregister", {force: false}, {attachedDeposit: "1"});
+
+    // Try to withdraw tokens
+    await operator.call(contract, "withdraw", {
+        token: token.accountId,
+        amount: agentBonds[0],
+        withdraw_storage: false
+    }, {gas: "300 Tgas"});
+
+    // The token balance is back in the registry
+    const result = await contract.view("get_token_balance", {token: token.accountId, account_id: operator.accountId});
+    t.is(result, agentBonds[0]);
+});
+
+test("Register the token storage of the refund receiver again and re-credit the failed refund", async t => {
+    const {root, contract, token, deployer} = t.context.accounts;
+
+    // Initialize the contract
+    await root.call(contract, "new", {
+        multisig_factory: deployer,
+        metadata: defaultContractMetadata
+    });
+
+    // Create the token service and activate its registration with the token security deposit
+    const attachedDeposit = "5 N";
+    await root.call(contract, "create", {
+        service_owner: deployer,
+        metadata: defaultServiceMetadata,
+        token: token.accountId,
+        config_hash: configHash,
+        agent_ids: agentIds,
+        agent_num_instances: agentNumInstances,
+        agent_bonds: agentBonds,
+        threshold
+    }, {attachedDeposit, gas: "300 Tgas"});
+    await deployer.call(token, "ft_transfer_call", {
+        receiver_id: contract.accountId,
+        amount: agentBonds[0].toString(),
+        msg: ""
+    }, {attachedDeposit: "1", gas: "300 Tgas"});
+    await deployer.call(contract, "activate_registration", {service_id: serviceId}, {attachedDeposit});
+
+    // The service owner withdraws its zero balance token storage and unregisters from the token contract
+    await deployer.call(contract, "storage_withdraw", {token: token.accountId});
+    const deployerBalance: string = await token.view("ft_balance_of", {account_id: deployer.accountId});
+    await deployer.call(token, "ft_transfer", {
+        receiver_id: root.accountId,
+        amount: deployerBalance
+    }, {attachedDeposit: "1"});
+    await deployer.call(token, "storage_unregister", {force: false}, {attachedDeposit: "1"});
+
+    // The security deposit refund fails, and it is credited to the token storage registered again by the termination
+    await deployer.call(contract, "terminate", {service_id: serviceId}, {attachedDeposit, gas: "300 Tgas"});
+    const result = await contract.view("get_token_balance", {token: token.accountId, account_id: deployer.accountId});
+    t.is(result, agentBonds[0]);
+});
+
+test("Keep the token storage when the withdrawal transfer with the storage withdrawal fails", async t => {
+    const {root, contract, token, deployer, operator} = t.context.accounts;
+
+    // Initialize the contract
+    await root.call(contract, "new", {
+        multisig_factory: deployer,
+        metadata: defaultContractMetadata
+    });
+
+    // Create service with the token to register it
+    const attachedDeposit = "5 N";
+    await root.call(contract, "create", {
+        service_owner: deployer,
+        metadata: defaultServiceMetadata,
+        token: token.accountId,
+        config_hash: configHash,
+        agent_ids: agentIds,
+        agent_num_instances: agentNumInstances,
+        agent_bonds: agentBonds,
+        threshold
+    }, {attachedDeposit, gas: "300 Tgas"});
+
+    // Register operator and send tokens to the registry contract
+    await operator.call(contract, "storage_deposit", {
+        token: token.accountId
+    }, {attachedDeposit});
+    await operator.call(token, "ft_transfer_call", {
+        receiver_id: contract.accountId,
+        amount: agentBonds[0].toString(),
+        msg: ""
+    }, {attachedDeposit: "1", gas: "300 Tgas"});
+
+    // Unregister the operator from the token contract
+    const operatorBalance: string = await token.view("ft_balance_of", {account_id: operator.accountId});
+    await operator.call(token, "ft_transfer", {
+        receiver_id: root.accountId,
+        amount: operatorBalance
+    }, {attachedDeposit: "1"});
+    await operator.call(token, "storage_unregister", {force: false}, {attachedDeposit: "1"});
+
+    // Try to withdraw tokens together with the token storage
+    const storageBefore: number = await contract.view("get_storage_usage", {});
+    await operator.call(contract, "withdraw", {
+        token: token.accountId,
+        amount: agentBonds[0],
+        withdraw_storage: true
+    }, {gas: "300 Tgas"});
+
+    // The token balance is back in the registry and its storage is still in place
+    let result = await contract.view("get_token_balance", {token: token.accountId, account_id: operator.accountId});
+    t.is(result, agentBonds[0]);
+    result = await contract.view("get_storage_usage", {});
+    t.is(result, storageBefore);
+
+    // Register the operator in the token contract again and withdraw tokens with the storage
+    await operator.call(token, "storage_deposit", {registration_only: true}, {attachedDeposit: "1 N"});
+    await operator.call(contract, "withdraw", {
+        token: token.accountId,
+        amount: agentBonds[0],
+        withdraw_storage: true
+    }, {gas: "300 Tgas"});
+
+    // The token storage is released after the successful transfer
+    result = await token.view("ft_balance_of", {account_id: operator.accountId});
+    t.is(result, agentBonds[0].toString());
+    const storageAfter: number = await contract.view("get_storage_usage", {});
+    t.true(storageAfter < storageBefore);
+    await t.throwsAsync(operator.call(contract, "storage_withdraw", {token: token.accountId}),
+        {message: /E016: Account not registered/});
+});
+
+test("Check native and token slashed funds and drain them", async t => {
+    const {root, contract, token, deployer} = t.context.accounts;
+
+    // Initialize the contract
+    await root.call(contract, "new", {
+        multisig_factory: deployer,
+        metadata: defaultContractMetadata
+    });
+
+    // Create services bonded in native NEAR and in the token
+    const attachedDeposit = "5 N";
+    await root.call(contract, "create", {
+        service_owner: deployer,
+        metadata: defaultServiceMetadata,
+        config_hash: configHash,
+        agent_ids: agentIds,
+        agent_num_instances: agentNumInstances,
+        agent_bonds: agentBonds,
+        threshold
+    }, {attachedDeposit});
+    await root.call(contract, "create", {
+        service_owner: deployer,
+        metadata: defaultServiceMetadata,
+        token: token.accountId,
+        config_hash: configHash,
+        agent_ids: agentIds,
+        agent_num_instances: agentNumInstances,
+        agent_bonds: agentBonds,
+        threshold
+    }, {attachedDeposit, gas: "300 Tgas"});
+
+    // Check slashed funds of both token kinds
+    let result = await contract.view("get_registry_slashed_funds", {token: "Native"});
+    t.is(result, 0);
+    result = await contract.view("get_registry_slashed_funds", {token: {FungibleToken: token.accountId}});
+    t.is(result, 0);
+
+    // Drain native slashed funds by the registry owner
+    await root.call(contract, "drain", {token: "Native"});
+
+    // Only the registry owner is able to drain
+    await t.throwsAsync(deployer.call(contract, "drain", {token: "Native"}), {message: /E001: Unauthorized/});
+});
+
+test("Slash the native service agent instance and drain slashed funds", async t => {
+    const {root, contract, deployer, operator, agentInstance} = t.context.accounts;
+
+    // Initialize the contract and approve the root as the factory of existing multisigs
+    await root.call(contract, "new", {
+        multisig_factory: deployer,
+        metadata: defaultContractMetadata
+    });
+    await root.call(contract, "add_multisig_factory", {multisig_factory: root, kind: "Multisig2"});
+
+    // Deploy the test multisig that returns preset members
+    const multisig = await root.createSubAccount("multisig", {initialBalance: NEAR.parse("10 N").toJSON()});
+    await multisig.deploy("target/wasm32-unknown-unknown/release/test_multisig_factory.wasm");
+    await multisig.call(multisig, "new", {});
+    await multisig.call(multisig, "set_members", {members: [{account_id: agentInstance.accountId}], num_confirmations: 1});
+
+    // Create, register and deploy the service bonded in native NEAR
+    const attachedDeposit = "5 N";
+    await root.call(contract, "create", {
+        service_owner: deployer,
+        metadata: defaultServiceMetadata,
+        config_hash: configHash,
+        agent_ids: agentIds,
+        agent_num_instances: agentNumInstances,
+        agent_bonds: agentBonds,
+        threshold
+    }, {attachedDeposit});
+    await deployer.call(contract, "activate_registration", {
+        service_id: serviceId,
+    }, {attachedDeposit});
+    await operator.call(contract, "register_agents", {
+        service_id: serviceId,
+        agent_instances: [agentInstance],
+        agent_ids: agentIds
+    }, {attachedDeposit});
+    await deployer.call(contract, "deploy", {
+        service_id: serviceId,
+        name_multisig: multisig
+    }, {gas: "300 Tgas"});
+
+    // Security deposit and the operator bond are accounted in the registry balance
+    let result = await contract.view("get_registry_balance", {});
+    t.is(result, 2 * agentBonds[0]);
+
+    // Only the service multisig is able to slash
+    await t.throwsAsync(operator.call(contract, "slash", {
+        agent_instances: [agentInstance],
+        amounts: [agentBonds[0]],
+        service_id: serviceId
+    }), {message: /E025: Wrong multisig/});
+
+    // Slash the agent instance by a part of its bond
+    const slashedAmount = 600;
+    await multisig.call(contract, "slash", {
+        agent_instances: [agentInstance],
+        amounts: [slashedAmount],
+        service_id: serviceId
+    });
+
+    // The slashed amount is moved from the registry balance into slashed funds
+    result = await contract.view("get_operator_balance", {operator, service_id: serviceId});
+    t.is(result, agentBonds[0] - slashedAmount);
+    result = await contract.view("get_registry_balance", {});
+    t.is(result, 2 * agentBonds[0] - slashedAmount);
+    result = await contract.view("get_registry_slashed_funds", {token: "Native"});
+    t.is(result, slashedAmount);
+
+    // Drain slashed funds, the registry balance stays the same
+    await root.call(contract, "drain", {token: "Native"});
+    result = await contract.view("get_registry_slashed_funds", {token: "Native"});
+    t.is(result, 0);
+    result = await contract.view("get_registry_balance", {});
+    t.is(result, 2 * agentBonds[0] - slashedAmount);
+});
+
+test("Get the service view", async t => {
+    const {root, contract, deployer} = t.context.accounts;
+
+    // Initialize the contract
+    await root.call(contract, "new", {
+        multisig_factory: deployer,
+        metadata: defaultContractMetadata
+    });
+
+    // Create service
+    const attachedDeposit = "5 N";
+    await root.call(contract, "create", {
+        service_owner: deployer,
+        metadata: defaultServiceMetadata,
+        config_hash: configHash,
+        agent_ids: agentIds,
+        agent_num_instances: agentNumInstances,
+        agent_bonds: agentBonds,
+        threshold
+    }, {attachedDeposit});
+
+    // Check the service view
+    const service: any = await contract.view("get_service", {service_id: serviceId});
+    t.is(service.owner_id, deployer.accountId);
+    t.is(service.token, null);
+    t.is(service.multisig, null);
+    t.is(service.state, "PreRegistration");
+    t.is(service.security_deposit, agentBonds[0].toString());
+    t.deepEqual(service.config_hash, configHash);
+    t.is(service.threshold, threshold);
+    t.is(service.max_num_agent_instances, 1);
+    t.is(service.num_agent_instances, 0);
+    t.deepEqual(service.agent_params, [{
+        agent_id: agentIds[0],
+        num_agent_instances: agentNumInstances[0],
+        bond: agentBonds[0].toString(),
+        instances: []
+    }]);
+    t.is(service.operators_check, false);
+
+    // Get the existing and a non-existent service
+    const services: any = await contract.view("get_services", {service_ids: [serviceId, serviceId + 1]});
+    t.is(services.length, 2);
+    t.is(services[0].service_id, serviceId);
+    t.is(services[1], null);
+});
+
+test("Enumerate services by state, owner and token", async t => {
+    const {root, contract, token, deployer} = t.context.accounts;
+
+    // Initialize the contract
+    await root.call(contract, "new", {
+        multisig_factory: deployer,
+        metadata: defaultContractMetadata
+    });
+
+    // Create two native services owned by the deployer and one token service owned by root
+    const attachedDeposit = "5 N";
+    for (let i = 0; i < 2; i++) {
+        await root.call(contract, "create", {
+            service_owner: deployer,
+            metadata: defaultServiceMetadata,
+            config_hash: configHash,
+            agent_ids: agentIds,
+            agent_num_instances: agentNumInstances,
+            agent_bonds: agentBonds,
+            threshold
+        }, {attachedDeposit});
+    }
+    await root.call(contract, "create", {
+        service_owner: root,
+        metadata: defaultServiceMetadata,
+        token: token.accountId,
+        config_hash: configHash,
+        agent_ids: agentIds,
+        agent_num_instances: agentNumInstances,
+        agent_bonds: agentBonds,
+        threshold
+    }, {attachedDeposit, gas: "300 Tgas"});
+
+    // Activate registration of the second service
+    await deployer.call(contract, "activate_registration", {
+        service_id: 2,
+    }, {attachedDeposit});
+
+    // Check services by state
+    let result: any = await contract.view("get_services_by_state", {state: "PreRegistration"});
+    t.deepEqual(result.map((s: any) => s.service_id), [1, 3]);
+    result = await contract.view("get_services_by_state", {state: "ActiveRegistration"});
+    t.deepEqual(result.map((s: any) => s.service_id), [2]);
+
+    // Check services by owner with pagination
+    result = await contract.view("get_services_by_owner", {owner_id: deployer, from_index: 1, limit: 1});
+    t.deepEqual(result.map((s: any) => s.service_id), [2]);
+
+    // Check services by token
+    result = await contract.view("get_services_by_token", {token: "Native"});
+    t.deepEqual(result.map((s: any) => s.service_id), [1, 2]);
+    result = await contract.view("get_services_by_token", {token: {FungibleToken: token.accountId}});
+    t.deepEqual(result.map((s: any) => s.service_id), [3]);
+});
+
+test("Check registry error codes", async t => {
+    const {root, contract, deployer} = t.context.accounts;
+
+    // Initialize the contract
+    await root.call(contract, "new", {
+        multisig_factory: deployer,
+        metadata: defaultContractMetadata
+    });
+
+    // Try to activate registration of a non-existent service
+    await t.throwsAsync(deployer.call(contract, "activate_registration", {
+        service_id: serviceId,
+    }, {attachedDeposit: "1 N"}), {message: /E003: Service not found/});
+
+    // Try to create a service with a wrong threshold
+    await t.throwsAsync(root.call(contract, "create", {
+        service_owner: deployer,
+        metadata: defaultServiceMetadata,
+        config_hash: configHash,
+        agent_ids: agentIds,
+        agent_num_instances: agentNumInstances,
+        agent_bonds: agentBonds,
+        threshold: 2
+    }, {attachedDeposit: "5 N"}), {message: /E007: Wrong threshold/});
+});
+
+test("Transfer the registry ownership in two steps", async t => {
+    const {root, contract, deployer, operator} = t.context.accounts;
+
+    // Initialize the contract
+    await root.call(contract, "new", {
+        multisig_factory: deployer,
+        metadata: defaultContractMetadata
+    });
+
+    // Propose the new owner
+    await root.call(contract, "propose_owner", {new_owner: deployer});
+    let result = await contract.view("get_pending_owner", {});
+    t.is(result, deployer.accountId);
+
+    // Only the pending owner is able to accept the ownership
+    await t.throwsAsync(operator.call(contract, "accept_ownership", {}), {message: /E001: Unauthorized/});
+    await deployer.call(contract, "accept_ownership", {});
+
+    result = await contract.view("get_owner", {});
+    t.is(result, deployer.accountId);
+    result = await contract.view("get_pending_owner", {});
+    t.is(result, null);
+
+    // The previous owner is not able to manage the registry anymore
+    await t.throwsAsync(root.call(contract, "set_paused", {paused: true}), {message: /E001: Unauthorized/});
+    await deployer.call(contract, "set_paused", {paused: true});
+
+    // Propose and cancel the owner proposal
+    await deployer.call(contract, "propose_owner", {new_owner: operator});
+    await deployer.call(contract, "cancel_owner_proposal", {});
+    await t.throwsAsync(operator.call(contract, "accept_ownership", {}), {message: /E001: Unauthorized/});
+});
+
+test("Grant and revoke registry roles", async t => {
+    const {root, contract, deployer, operator} = t.context.accounts;
+
+    // Initialize the contract
+    await root.call(contract, "new", {
+        multisig_factory: deployer,
+        metadata: defaultContractMetadata
+    });
+
+    // The operator is not able to pause the registry without the pauser role
+    await t.throwsAsync(operator.call(contract, "set_paused", {paused: true}), {message: /E001: Unauthorized/});
+
+    // Only the owner is able to grant roles
+    await t.throwsAsync(operator.call(contract, "grant_role", {role: "Pauser", account_id: operator}),
+        {message: /E001: Unauthorized/});
+    await root.call(contract, "grant_role", {role: "Pauser", account_id: operator});
+    let result = await contract.view("get_role_holders", {role: "Pauser"});
+    t.deepEqual(result, [operator.accountId]);
+
+    // The pauser is able to pause the registry, but not to drain it
+    await operator.call(contract, "set_paused", {paused: true});
+    result = await contract.view("is_paused", {});
+    t.is(result, true);
+    await t.throwsAsync(operator.call(contract, "drain", {token: "Native"}), {message: /E001: Unauthorized/});
+
+    // Revoke the role
+    await root.call(contract, "revoke_role", {role: "Pauser", account_id: operator});
+    result = await contract.view("has_role", {role: "Pauser", account_id: operator});
+    t.is(result, false);
+    await t.throwsAsync(operator.call(contract, "set_paused", {paused: false}), {message: /E001: Unauthorized/});
+});
+
+test("Upgrade the contract and migrate its state", async t => {
+    const {root, contract, deployer} = t.context.accounts;
+
+    // Initialize the contract
+    await root.call(contract, "new", {
+        multisig_factory: deployer,
+        metadata: defaultContractMetadata
+    });
+
+    // Create service
+    await root.call(contract, "create", {
+        service_owner: deployer,
+        metadata: defaultServiceMetadata,
+        config_hash: configHash,
+        agent_ids: agentIds,
+        agent_num_instances: agentNumInstances,
+        agent_bonds: agentBonds,
+        threshold
+    }, {attachedDeposit: "5 N"});
+
+    // Stage the upgrade hash
+    const code = fs.readFileSync("target/wasm32-unknown-unknown/release/registries_near.wasm");
+    const hash = Array.from(crypto.createHash("sha256").update(code).digest());
+    await root.call(contract, "change_upgrade_hash", {hash});
+
+    // The upgrade is timelocked by the default upgrade delay
+    await t.throwsAsync(root.call(contract, "upgrade_contract", code, {gas: "300 Tgas"}), {message: /E029/});
+    const upgrade: any = await contract.view("get_pending_upgrade", {});
+    await fastForwardTo(t.context.worker, upgrade.activation_ts);
+
+    // Upgrade the contract, which migrates the state within the same batch
+    await root.call(contract, "upgrade_contract", code, {gas: "300 Tgas"});
+
+    // Check the state version and that the service is preserved
+    let result = await contract.view("get_state_version", {});
+    t.is(result, 8);
+    result = await contract.view("get_service_state", {service_id: serviceId});
+    t.is(result, 1);
+});
+
+test("Upgrade the baseline contract with live services and migrate its state", async t => {
+    const {root, deployer, operator, agentInstance} = t.context.accounts;
+
+    // Deploy the registry contract of the baseline version
+    const contract = await root.devDeploy(
+        "artifacts/registries_near_baseline.wasm",
+        {initialBalance: NEAR.parse("20 N").toJSON()},
+    );
+    await root.call(contract, "new", {
+        multisig_factory: deployer,
+        metadata: defaultContractMetadata
+    });
+
+    // Create two services and register the agent instance of the first one
+    const attachedDeposit = "5 N";
+    for (let i = 0; i < 2; i++) {
+        await root.call(contract, "create", {
+            service_owner: deployer,
+            metadata: defaultServiceMetadata,
+            config_hash: configHash,
+            agent_ids: agentIds,
+            agent_num_instances: agentNumInstances,
+            agent_bonds: agentBonds,
+            threshold
+        }, {attachedDeposit});
+    }
+    await deployer.call(contract, "activate_registration", {
+        service_id: serviceId,
+    }, {attachedDeposit});
+    await operator.call(contract, "register_agents", {
+        service_id: serviceId,
+        agent_instances: [agentInstance],
+        agent_ids: agentIds
+    }, {attachedDeposit});
+
+    // Upgrade the contract with the baseline upgrade flow and migrate the registry state
+    const code = fs.readFileSync("target/wasm32-unknown-unknown/release/registries_near.wasm");
+    const hash = Array.from(crypto.createHash("sha256").update(code).digest());
+    await root.call(contract, "change_upgrade_hash", {hash});
+    await root.call(contract, "upgrade_contract", code, {gas: "300 Tgas"});
+    await contract.call(contract, "migrate", {}, {gas: "300 Tgas"});
+
+    // Check the state version and the registry fields of the baseline state
+    let result: any = await contract.view("get_state_version", {});
+    t.is(result, 8);
+    result = await contract.view("get_owner", {});
+    t.is(result, root.accountId);
+    result = await contract.view("get_registry_balance", {});
+    t.is(result, 2 * agentBonds[0]);
+    result = await contract.view("get_multisig_factories", {});
+    t.deepEqual(result, [{multisig_factory: deployer.accountId, kind: "Multisig2", is_default: true}]);
+
+    // Check that live services are preserved
+    result = await contract.view("get_service_state", {service_id: serviceId});
+    t.is(result, 3);
+    result = await contract.view("get_service_state", {service_id: serviceId + 1});
+    t.is(result, 1);
+    result = await contract.view("get_service_agent_instances", {service_id: serviceId});
+    t.deepEqual(result, [agentInstance.accountId]);
+
+    // Migrating the state of the current version keeps it as is
+    await contract.call(contract, "migrate", {}, {gas: "300 Tgas"});
+    result = await contract.view("get_state_version", {});
+    t.is(result, 8);
+    result = await contract.view("get_service_state", {service_id: serviceId});
+    t.is(result, 3);
+});
+
+test("Stage the timelocked upgrade, cancel it and change the upgrade delay", async t => {
+    const {root, contract, deployer} = t.context.accounts;
+
+    // Initialize the contract
+    await root.call(contract, "new", {
+        multisig_factory: deployer,
+        metadata: defaultContractMetadata
+    });
+
+    // The upgrade delay is one day by default
+    let result: any = await contract.view("get_upgrade_delay", {});
+    t.is(result, 86400);
+
+    // Only the owner is able to change the upgrade delay, and its increase takes effect immediately
+    await t.throwsAsync(deployer.call(contract, "change_upgrade_delay", {delay: 172800}), {message: /E001: Unauthorized/});
+    await root.call(contract, "change_upgrade_delay", {delay: 172800});
+    result = await contract.view("get_upgrade_delay", {});
+    t.is(result, 172800);
+
+    // Stage the upgrade hash
+    const code = fs.readFileSync("target/wasm32-unknown-unknown/release/registries_near.wasm");
+    const hash = Array.from(crypto.createHash("sha256").update(code).digest());
+    await root.call(contract, "change_upgrade_hash", {hash});
+    result = await contract.view("get_pending_upgrade", {});
+    t.is(result.hash, Buffer.from(hash).toString("hex"));
+
+    // The upgrade is not possible before the delay passes
+    await t.throwsAsync(root.call(contract, "upgrade_contract", code, {gas: "300 Tgas"}), {message: /E029/});
+
+    // Cancel the upgrade
+    await root.call(contract, "cancel_upgrade", {});
+    result = await contract.view("get_pending_upgrade", {});
+    t.is(result, null);
+
+    // Decreasing the delay is only applied after the current delay
+    await root.call(contract, "change_upgrade_delay", {delay: 3600});
+    result = await contract.view("get_upgrade_delay", {});
+    t.is(result, 172800);
+    result = await contract.view("get_pending_upgrade_delay", {});
+    t.is(result.delay, 3600);
+});
+
+test("Approve multisig factories and deploy the service with the selected one", async t => {
+    const {root, contract, deployer, operator, agentInstance} = t.context.accounts;
+
+    // Deploy and initialize the test multisig factory
+    const factory = await root.devDeploy(
+        "target/wasm32-unknown-unknown/release/test_multisig_factory.wasm",
+        {initialBalance: NEAR.parse("10 N").toJSON()},
+    );
+    await root.call(factory, "new", {});
+
+    // Initialize the contract with the default factory
+    await root.call(contract, "new", {
+        multisig_factory: deployer,
+        metadata: defaultContractMetadata
+    });
+
+    // The factory must be approved before becoming the default one
+    await t.throwsAsync(root.call(contract, "change_multisig_factory", {multisig_factory: factory}), {message: /E030/});
+    await t.throwsAsync(operator.call(contract, "add_multisig_factory", {multisig_factory: factory, kind: "Multisig2"}),
+        {message: /E001: Unauthorized/});
+    await root.call(contract, "add_multisig_factory", {multisig_factory: factory, kind: "Multisig2"});
+    let result: any = await contract.view("get_multisig_factories", {});
+    t.is(result.length, 2);
+
+    // The default factory cannot be removed
+    await t.throwsAsync(root.call(contract, "remove_multisig_factory", {multisig_factory: deployer}), {message: /E031/});
+
+    // Create service, activate its registration and register agent instances
+    const attachedDeposit = "5 N";
+    await root.call(contract, "create", {
+        service_owner: deployer,
+        metadata: defaultServiceMetadata,
+        config_hash: configHash,
+        agent_ids: agentIds,
+        agent_num_instances: agentNumInstances,
+        agent_bonds: agentBonds,
+        threshold
+    }, {attachedDeposit});
+    await deployer.call(contract, "activate_registration", {
+        service_id: serviceId,
+    }, {attachedDeposit});
+    await operator.call(contract, "register_agents", {
+        service_id: serviceId,
+        agent_instances: [agentInstance],
+        agent_ids: agentIds
+    }, {attachedDeposit});
+
+    // Deploy the service with the approved non-default factory
+    await deployer.call(contract, "deploy", {
+        service_id: serviceId,
+        name_multisig: "multisig",
+        multisig_factory: factory
+    }, {attachedDeposit, gas: "300 Tgas"});
+    result = await contract.view("get_service_multisig", {service_id: serviceId});
+    t.is(result, "multisig." + factory.accountId);
+});
+
+test("Deploy the service with the Sputnik DAO factory", async t => {
+    const {root, contract, deployer, operator, agentInstance} = t.context.accounts;
+
+    // Deploy and initialize the test multisig factory that also accepts the DAO factory arguments
+    const factory = await root.devDeploy(
+        "target/wasm32-unknown-unknown/release/test_multisig_factory.wasm",
+        {initialBalance: NEAR.parse("10 N").toJSON()},
+    );
+    await root.call(factory, "new", {});
+
+    // Initialize the contract and approve the DAO factory
+    await root.call(contract, "new", {
+        multisig_factory: deployer,
+        metadata: defaultContractMetadata
+    });
+    await root.call(contract, "add_multisig_factory", {multisig_factory: factory, kind: "SputnikDao"});
+
+    // Create service, activate its registration and register agent instances
+    const attachedDeposit = "5 N";
+    await root.call(contract, "create", {
+        service_owner: deployer,
+        metadata: defaultServiceMetadata,
+        config_hash: configHash,
+        agent_ids: agentIds,
+        agent_num_instances: agentNumInstances,
+        agent_bonds: agentBonds,
+        threshold
+    }, {attachedDeposit});
+    await deployer.call(contract, "activate_registration", {
+        service_id: serviceId,
+    }, {attachedDeposit});
+    await operator.call(contract, "register_agents", {
+        service_id: serviceId,
+        agent_instances: [agentInstance],
+        agent_ids: agentIds
+    }, {attachedDeposit});
+
+    // Deploy the service with the DAO
+    const outcome = await deployer.callRaw(contract, "deploy", {
+        service_id: serviceId,
+        name_multisig: "dao",
+        multisig_factory: factory
+    }, {attachedDeposit, gas: "300 Tgas"});
+    const event = parseEvents(outcome).find(e => e.event === "deploy_service");
+    t.is(event.data.multisig, "dao." + factory.accountId);
+
+    // The service records the full account id of the created DAO
+    let result = await contract.view("get_service_state", {service_id: serviceId});
+    t.is(result, 4);
+    result = await contract.view("get_service_multisig", {service_id: serviceId});
+    t.is(result, "dao." + factory.accountId);
+    t.true(await root.getAccount("dao." + factory.accountId).exists());
+});
+
+test("Report the mismatch of the existing Sputnik DAO policy and deploy the service after the DAO is fixed", async t => {
+    const {root, contract, deployer, operator, agentInstance, agentInstance2} = t.context.accounts;
+
+    // Initialize the contract and approve the root as the factory of existing DAOs
+    await root.call(contract, "new", {
+        multisig_factory: deployer,
+        metadata: defaultContractMetadata
+    });
+    await root.call(contract, "add_multisig_factory", {multisig_factory: root, kind: "SputnikDao"});
+
+    // Deploy the test multisig that returns the DAO policy with another council member
+    const dao = await root.createSubAccount("dao", {initialBalance: NEAR.parse("10 N").toJSON()});
+    await dao.deploy("target/wasm32-unknown-unknown/release/test_multisig_factory.wasm");
+    await dao.call(dao, "new", {});
+    await dao.call(dao, "set_members", {members: [{account_id: agentInstance2.accountId}], num_confirmations: 1});
+
+    // Create service, activate its registration and register agent instances
+    const attachedDeposit = "5 N";
+    await root.call(contract, "create", {
+        service_owner: deployer,
+        metadata: defaultServiceMetadata,
+        config_hash: configHash,
+        agent_ids: agentIds,
+        agent_num_instances: agentNumInstances,
+        agent_bonds: agentBonds,
+        threshold
+    }, {attachedDeposit});
+    await deployer.call(contract, "activate_registration", {
+        service_id: serviceId,
+    }, {attachedDeposit});
+    await operator.call(contract, "register_agents", {
+        service_id: serviceId,
+        agent_instances: [agentInstance],
+        agent_ids: agentIds
+    }, {attachedDeposit});
+
+    // The council mismatch is reported and the service is not deployed
+    const outcome = await deployer.callRaw(contract, "deploy", {
+        service_id: serviceId,
+        name_multisig: dao
+    }, {gas: "300 Tgas"});
+    const event = parseEvents(outcome).find(e => e.event === "multisig_members_mismatch");
+    t.deepEqual(event.data.missing, [{account_id: agentInstance.accountId}]);
+    t.deepEqual(event.data.extra, [{account_id: agentInstance2.accountId}]);
+    t.is(event.data.num_confirmations, 1);
+    let result = await contract.view("get_service_state", {service_id: serviceId});
+    t.is(result, 3);
+
+    // The service is deployed once the DAO council matches agent instances
+    await dao.call(dao, "set_members", {members: [{account_id: agentInstance.accountId}], num_confirmations: 1});
+    await deployer.call(contract, "deploy", {
+        service_id: serviceId,
+        name_multisig: dao
+    }, {gas: "300 Tgas"});
+    result = await contract.view("get_service_state", {service_id: serviceId});
+    t.is(result, 4);
+});
+
+test("Register agent instances with public keys and deploy the access key multisig", async t => {
+    const {root, contract, deployer, operator, agentInstance} = t.context.accounts;
+
+    // Deploy and initialize the test multisig factory
+    const factory = await root.devDeploy(
+        "target/wasm32-unknown-unknown/release/test_multisig_factory.wasm",
+        {initialBalance: NEAR.parse("10 N").toJSON()},
+    );
+    await root.call(factory, "new", {});
+
+    // Initialize the contract
+    await root.call(contract, "new", {
+        multisig_factory: factory,
+        metadata: defaultContractMetadata
+    });
+
+    // Create service and activate its registration
+    const attachedDeposit = "5 N";
+    await root.call(contract, "create", {
+        service_owner: deployer,
+        metadata: defaultServiceMetadata,
+        config_hash: configHash,
+        agent_ids: agentIds,
+        agent_num_instances: agentNumInstances,
+        agent_bonds: agentBonds,
+        threshold
+    }, {attachedDeposit});
+    await deployer.call(contract, "activate_registration", {
+        service_id: serviceId,
+    }, {attachedDeposit});
+
+    // The implicit account agent instance must match its public key
+    const publicKey = (await agentInstance.getKey()).getPublicKey().toString();
+    await t.throwsAsync(operator.call(contract, "register_agents", {
+        service_id: serviceId,
+        agent_instances: ["0".repeat(64)],
+        agent_ids: agentIds,
+        public_keys: [publicKey]
+    }, {attachedDeposit}), {message: /E032/});
+
+    // Register the agent instance with its public key
+    await operator.call(contract, "register_agents", {
+        service_id: serviceId,
+        agent_instances: [agentInstance],
+        agent_ids: agentIds,
+        public_keys: [publicKey]
+    }, {attachedDeposit});
+    let result = await contract.view("get_agent_instance_public_key", {agent_instance: agentInstance});
+    t.is(result, publicKey);
+
+    // Deploy the service with the access key multisig member
+    await deployer.call(contract, "deploy", {
+        service_id: serviceId,
+        name_multisig: "multisig"
+    }, {attachedDeposit, gas: "300 Tgas"});
+    result = await contract.view("get_service_state", {service_id: serviceId});
+    t.is(result, 4);
+});
+
+test("Register agent instances with their consent", async t => {
+    const {root, contract, deployer, operator, agentInstance} = t.context.accounts;
+
+    // Initialize the contract and require the agent instance consent
+    await root.call(contract, "new", {
+        multisig_factory: deployer,
+        metadata: defaultContractMetadata
+    });
+    await root.call(contract, "set_instance_consent_required", {required: true});
+
+    // Create service with two agent instances and activate its registration
+    const attachedDeposit = "5 N";
+    await root.call(contract, "create", {
+        service_owner: deployer,
+        metadata: defaultServiceMetadata,
+        config_hash: configHash,
+        agent_ids: agentIds,
+        agent_num_instances: [2],
+        agent_bonds: agentBonds,
+        threshold: 2
+    }, {attachedDeposit});
+    await deployer.call(contract, "activate_registration", {
+        service_id: serviceId,
+    }, {attachedDeposit});
+
+    // The agent instance is not registered without its consent
+    await t.throwsAsync(operator.call(contract, "register_agents", {
+        service_id: serviceId,
+        agent_instances: [agentInstance],
+        agent_ids: agentIds
+    }, {attachedDeposit}), {message: /E033/});
+
+    // The agent instance pays for the consent storage, accepts the operator and gets registered
+    await t.throwsAsync(agentInstance.call(contract, "accept_operator", {service_id: serviceId, operator}),
+        {message: /E013: Insufficient deposit/});
+    await agentInstance.call(contract, "accept_operator", {service_id: serviceId, operator}, {attachedDeposit: "0.1 N"});
+    await operator.call(contract, "register_agents", {
+        service_id: serviceId,
+        agent_instances: [agentInstance],
+        agent_ids: agentIds
+    }, {attachedDeposit});
+    let result: any = await contract.view("get_instance_consent", {agent_instance: agentInstance});
+    t.is(result, null);
+
+    // The implicit account agent instance signs its consent
+    const keyPair = KeyPair.fromRandom("ed25519");
+    const implicitAccount = Buffer.from(keyPair.getPublicKey().data).toString("hex");
+    const message = Buffer.from(`${contract.accountId}:${serviceId}:${operator.accountId}`);
+    const signature = Buffer.from(keyPair.sign(message).signature).toString("base64");
+    const wrongSignature = Buffer.from(keyPair.sign(Buffer.from("wrong")).signature).toString("base64");
+    await t.throwsAsync(operator.call(contract, "register_agents", {
+        service_id: serviceId,
+        agent_instances: [implicitAccount],
+        agent_ids: agentIds,
+        signatures: [wrongSignature]
+    }, {attachedDeposit}), {message: /E034/});
+    await operator.call(contract, "register_agents", {
+        service_id: serviceId,
+        agent_instances: [implicitAccount],
+        agent_ids: agentIds,
+        signatures: [signature]
+    }, {attachedDeposit});
+
+    result = await contract.view("get_service_state", {service_id: serviceId});
+    t.is(result, 3);
+});
+
+test("Deploy the service with the existing multisig having members in a different order", async t => {
+    const {root, contract, deployer, operator, agentInstance, agentInstance2} = t.context.accounts;
+
+    // Initialize the contract and approve the root as the factory of existing multisigs
+    await root.call(contract, "new", {
+        multisig_factory: deployer,
+        metadata: defaultContractMetadata
+    });
+    await root.call(contract, "add_multisig_factory", {multisig_factory: root, kind: "Multisig2"});
+
+    // Deploy the test multisig that returns preset members
+    const multisig = await root.createSubAccount("multisig", {initialBalance: NEAR.parse("10 N").toJSON()});
+    await multisig.deploy("target/wasm32-unknown-unknown/release/test_multisig_factory.wasm");
+    await multisig.call(multisig, "new", {});
+
+    // Create service with two agent instances and register them
+    const attachedDeposit = "5 N";
+    await root.call(contract, "create", {
+        service_owner: deployer,
+        metadata: defaultServiceMetadata,
+        config_hash: configHash,
+        agent_ids: agentIds,
+        agent_num_instances: [2],
+        agent_bonds: agentBonds,
+        threshold: 2
+    }, {attachedDeposit});
+    await deployer.call(contract, "activate_registration", {
+        service_id: serviceId,
+    }, {attachedDeposit});
+    await operator.call(contract, "register_agents", {
+        service_id: serviceId,
+        agent_instances: [agentInstance, agentInstance2],
+        agent_ids: [1, 1]
+    }, {attachedDeposit});
+
+    // Multisig members are in the reverse order, but the number of confirmations does not match the threshold
+    const members = [{account_id: agentInstance2.accountId}, {account_id: agentInstance.accountId}];
+    await multisig.call(multisig, "set_members", {members, num_confirmations: 1});
+    const outcome = await deployer.callRaw(contract, "deploy", {
+        service_id: serviceId,
+        name_multisig: multisig
+    }, {gas: "300 Tgas"});
+    const event = parseEvents(outcome).find(e => e.event === "multisig_members_mismatch");
+    t.truthy(event);
+    t.deepEqual(event.data.missing, []);
+    t.deepEqual(event.data.extra, []);
+    t.is(event.data.num_confirmations, 1);
+    let result = await contract.view("get_service_state", {service_id: serviceId});
+    t.is(result, 3);
+
+    // Deploy the service with the matching number of confirmations
+    await multisig.call(multisig, "set_members", {members, num_confirmations: 2});
+    await deployer.call(contract, "deploy", {
+        service_id: serviceId,
+        name_multisig: multisig
+    }, {gas: "300 Tgas"});
+    result = await contract.view("get_service_state", {service_id: serviceId});
+    t.is(result, 4);
+});
+
+test("Report the mismatch of only the number of confirmations of the existing multisig", async t => {
+    const {root, contract, deployer, operator, agentInstance, agentInstance2} = t.context.accounts;
+
+    // Initialize the contract and approve the root as the factory of existing multisigs
+    await root.call(contract, "new", {
+        multisig_factory: deployer,
+        metadata: defaultContractMetadata
+    });
+    await root.call(contract, "add_multisig_factory", {multisig_factory: root, kind: "Multisig2"});
+
+    // Create service with two agent instances and register them
+    const attachedDeposit = "5 N";
+    await root.call(contract, "create", {
+        service_owner: deployer,
+        metadata: defaultServiceMetadata,
+        config_hash: configHash,
+        agent_ids: agentIds,
+        agent_num_instances: [2],
+        agent_bonds: agentBonds,
+        threshold: 2
+    }, {attachedDeposit});
+    await deployer.call(contract, "activate_registration", {
+        service_id: serviceId,
+    }, {attachedDeposit});
+    await operator.call(contract, "register_agents", {
+        service_id: serviceId,
+        agent_instances: [agentInstance, agentInstance2],
+        agent_ids: [1, 1]
+    }, {attachedDeposit});
+
+    // Deploy the test multisig with exactly the agent instances as members, but a different number of confirmations
+    const multisig = await root.createSubAccount("multisig", {initialBalance: NEAR.parse("10 N").toJSON()});
+    await multisig.deploy("target/wasm32-unknown-unknown/release/test_multisig_factory.wasm");
+    await multisig.call(multisig, "new", {});
+    const members = [{account_id: agentInstance.accountId}, {account_id: agentInstance2.accountId}];
+    await multisig.call(multisig, "set_members", {members, num_confirmations: 1});
+
+    // Only the threshold mismatch is reported and the service stays in the FinishedRegistration state
+    const outcome = await deployer.callRaw(contract, "deploy", {
+        service_id: serviceId,
+        name_multisig: multisig
+    }, {gas: "300 Tgas"});
+    const event = parseEvents(outcome).find(e => e.event === "multisig_members_mismatch");
+    t.truthy(event);
+    t.deepEqual(event.data.missing, []);
+    t.deepEqual(event.data.extra, []);
+    t.is(event.data.num_confirmations, 1);
+    t.is(event.data.threshold, 2);
+    let result = await contract.view("get_service_state", {service_id: serviceId});
+    t.is(result, 3);
+    result = await contract.view("get_service_multisig", {service_id: serviceId});
+    t.is(result, null);
+});
+
+test("Register agent instances with the operators whitelisting check", async t => {
+    const {root, contract, deployer, operator, agentInstance, agentInstance2} = t.context.accounts;
+
+    // Initialize the contract
+    await root.call(contract, "new", {
+        multisig_factory: deployer,
+        metadata: defaultContractMetadata
+    });
+
+    // Create service with two agent instances and activate its registration
+    const attachedDeposit = "5 N";
+    await root.call(contract, "create", {
+        service_owner: deployer,
+        metadata: defaultServiceMetadata,
+        config_hash: configHash,
+        agent_ids: agentIds,
+        agent_num_instances: [3],
+        agent_bonds: agentBonds,
+        threshold: 3
+    }, {attachedDeposit});
+    await deployer.call(contract, "activate_registration", {
+        service_id: serviceId,
+    }, {attachedDeposit});
+
+    // Enable the operators check, such that unknown operators are not whitelisted
+    await deployer.call(contract, "set_operators_check", {service_id: serviceId, set_check: true});
+    let result = await contract.view("is_operator_whitelisted", {service_id: serviceId, operator});
+    t.is(result, false);
+
+    // The blocked operator is not able to register agent instances
+    await t.throwsAsync(operator.call(contract, "register_agents", {
+        service_id: serviceId,
+        agent_instances: [agentInstance],
+        agent_ids: agentIds
+    }, {attachedDeposit}), {message: /E035/});
+
+    // The service owner is always whitelisted
+    const ownerInstance = await root.createSubAccount("owner_instance", {initialBalance: NEAR.parse("1 N").toJSON()});
+    await deployer.call(contract, "register_agents", {
+        service_id: serviceId,
+        agent_instances: [ownerInstance],
+        agent_ids: agentIds
+    }, {attachedDeposit});
+
+    // The whitelisted operator registers agent instances
+    await deployer.call(contract, "set_operators_statuses", {
+        service_id: serviceId,
+        operators: [operator],
+        statuses: [true],
+        set_check: true
+    }, {attachedDeposit});
+    await operator.call(contract, "register_agents", {
+        service_id: serviceId,
+        agent_instances: [agentInstance, agentInstance2],
+        agent_ids: [1, 1]
+    }, {attachedDeposit});
+
+    result = await contract.view("get_service_state", {service_id: serviceId});
+    t.is(result, 3);
+});
+
+test("Register agent instances with the operators merkle proof", async t => {
+    const {root, contract, deployer, operator, agentInstance} = t.context.accounts;
+
+    // Initialize the contract
+    await root.call(contract, "new", {
+        multisig_factory: deployer,
+        metadata: defaultContractMetadata
+    });
+
+    // Create service and activate its registration
+    const attachedDeposit = "5 N";
+    await root.call(contract, "create", {
+        service_owner: deployer,
+        metadata: defaultServiceMetadata,
+        config_hash: configHash,
+        agent_ids: agentIds,
+        agent_num_instances: agentNumInstances,
+        agent_bonds: agentBonds,
+        threshold
+    }, {attachedDeposit});
+    await deployer.call(contract, "activate_registration", {
+        service_id: serviceId,
+    }, {attachedDeposit});
+
+    // Build the merkle tree of two operators with sorted pairs hashing
+    const sha256 = (data: Buffer) => crypto.createHash("sha256").update(data).digest();
+    const leaf = sha256(Buffer.from(operator.accountId));
+    const sibling = sha256(Buffer.from("other_operator.test.near"));
+    const merkleRoot = sha256(Buffer.concat([leaf, sibling].sort(Buffer.compare)));
+    await deployer.call(contract, "set_operators_check", {service_id: serviceId, set_check: true});
+    // The service owner pays for the merkle root storage
+    await t.throwsAsync(deployer.call(contract, "set_operators_root", {service_id: serviceId, root: Array.from(merkleRoot)}),
+        {message: /E013: Insufficient deposit/});
+    await deployer.call(contract, "set_operators_root", {
+        service_id: serviceId,
+        root: Array.from(merkleRoot)
+    }, {attachedDeposit: "0.1 N"});
+
+    // The wrong proof is rejected
+    await t.throwsAsync(operator.call(contract, "register_agents", {
+        service_id: serviceId,
+        agent_instances: [agentInstance],
+        agent_ids: agentIds,
+        operator_proof: [Array(32).fill(1)]
+    }, {attachedDeposit}), {message: /E035/});
+
+    // Register agent instances with the proof
+    const proof = [Array.from(sibling)];
+    let result = await contract.view("is_operator_allowed", {service_id: serviceId, operator, proof});
+    t.is(result, true);
+    await operator.call(contract, "register_agents", {
+        service_id: serviceId,
+        agent_instances: [agentInstance],
+        agent_ids: agentIds,
+        operator_proof: proof
+    }, {attachedDeposit});
+
+    result = await contract.view("get_service_state", {service_id: serviceId});
+    t.is(result, 3);
+
+    // Removing the merkle root releases its storage
+    const storageBefore: number = await contract.view("get_storage_usage", {});
+    await deployer.call(contract, "set_operators_root", {service_id: serviceId, root: null});
+    const storageAfter: number = await contract.view("get_storage_usage", {});
+    t.true(storageAfter < storageBefore);
+});
+
+test("Unregister agent instances during the active registration", async t => {
+    const {root, contract, deployer, operator, agentInstance, agentInstance2} = t.context.accounts;
+
+    // Initialize the contract
+    await root.call(contract, "new", {
+        multisig_factory: deployer,
+        metadata: defaultContractMetadata
+    });
+
+    // Create service with two agent instances and activate its registration
+    const attachedDeposit = "5 N";
+    await root.call(contract, "create", {
+        service_owner: deployer,
+        metadata: defaultServiceMetadata,
+        config_hash: configHash,
+        agent_ids: agentIds,
+        agent_num_instances: [2],
+        agent_bonds: agentBonds,
+        threshold: 2
+    }, {attachedDeposit});
+    await deployer.call(contract, "activate_registration", {
+        service_id: serviceId,
+    }, {attachedDeposit});
+
+    // Register one agent instance
+    await operator.call(contract, "register_agents", {
+        service_id: serviceId,
+        agent_instances: [agentInstance],
+        agent_ids: agentIds
+    }, {attachedDeposit});
+
+    // Only the operator of the agent instance is able to unregister it
+    await t.throwsAsync(deployer.call(contract, "unregister_agents", {
+        service_id: serviceId,
+        agent_instances: [agentInstance]
+    }), {message: /E027/});
+
+    // Unregister the agent instance
+    await operator.call(contract, "unregister_agents", {
+        service_id: serviceId,
+        agent_instances: [agentInstance]
+    });
+    let result = await contract.view("get_service_agent_instances", {service_id: serviceId});
+    t.deepEqual(result, []);
+    result = await contract.view("get_registry_balance", {});
+    t.is(result, agentBonds[0]);
+
+    // The freed slots are available for registration again, including the same agent instance
+    await operator.call(contract, "register_agents", {
+        service_id: serviceId,
+        agent_instances: [agentInstance, agentInstance2],
+        agent_ids: [1, 1]
+    }, {attachedDeposit});
+    result = await contract.view("get_service_state", {service_id: serviceId});
+    t.is(result, 3);
+
+    // Agent instances cannot be unregistered after the registration is finished
+    await t.throwsAsync(operator.call(contract, "unregister_agents", {
+        service_id: serviceId,
+        agent_instances: [agentInstance]
+    }), {message: /E004/});
+});
+
+test("Unregister agent instances of the native and token services and check the registry balance", async t => {
+    const {root, contract, token, deployer, operator, agentInstance, agentInstance2} = t.context.accounts;
+
+    // Initialize the contract
+    await root.call(contract, "new", {
+        multisig_factory: deployer,
+        metadata: defaultContractMetadata
+    });
+
+    // Create the native service and the token service with two agent instances each
+    const attachedDeposit = "5 N";
+    await root.call(contract, "create", {
+        service_owner: deployer,
+        metadata: defaultServiceMetadata,
+        config_hash: configHash,
+        agent_ids: agentIds,
+        agent_num_instances: [2],
+        agent_bonds: agentBonds,
+        threshold: 2
+    }, {attachedDeposit});
+    await root.call(contract, "create", {
+        service_owner: deployer,
+        metadata: defaultServiceMetadata,
+        token: token.accountId,
+        config_hash: configHash,
+        agent_ids: agentIds,
+        agent_num_instances: [2],
+        agent_bonds: agentBonds,
+        threshold: 2
+    }, {attachedDeposit, gas: "300 Tgas"});
+
+    // Activate both registrations, the token security deposit is transferred beforehand
+    await deployer.call(contract, "activate_registration", {service_id: 1}, {attachedDeposit});
+    await deployer.call(token, "ft_transfer_call", {
+        receiver_id: contract.accountId,
+        amount: agentBonds[0].toString(),
+        msg: ""
+    }, {attachedDeposit: "1", gas: "300 Tgas"});
+    await deployer.call(contract, "activate_registration", {service_id: 2}, {attachedDeposit});
+
+    // Register one agent instance in each service
+    await operator.call(contract, "register_agents", {
+        service_id: 1,
+        agent_instances: [agentInstance],
+        agent_ids: agentIds
+    }, {attachedDeposit});
+    await operator.call(contract, "storage_deposit", {token: token.accountId}, {attachedDeposit});
+    await operator.call(token, "ft_transfer_call", {
+        receiver_id: contract.accountId,
+        amount: agentBonds[0].toString(),
+        msg: ""
+    }, {attachedDeposit: "1", gas: "300 Tgas"});
+    await operator.call(contract, "register_agents", {
+        service_id: 2,
+        agent_instances: [agentInstance2],
+        agent_ids: agentIds
+    }, {attachedDeposit});
+
+    // The registry balance only accounts the native security deposit and bond
+    let result = await contract.view("get_registry_balance", {});
+    t.is(result, 2 * agentBonds[0]);
+
+    // Unregistering the token agent instance sends the token bond back and keeps the registry balance
+    const operatorTokenBalance: string = await token.view("ft_balance_of", {account_id: operator.accountId});
+    await operator.call(contract, "unregister_agents", {
+        service_id: 2,
+        agent_instances: [agentInstance2]
+    }, {gas: "300 Tgas"});
+    const operatorTokenBalanceAfter: string = await token.view("ft_balance_of", {account_id: operator.accountId});
+    t.is(BigInt(operatorTokenBalanceAfter) - BigInt(operatorTokenBalance), BigInt(agentBonds[0]));
+    result = await contract.view("get_registry_balance", {});
+    t.is(result, 2 * agentBonds[0]);
+
+    // Unregistering the native agent instance decreases the registry balance by its bond
+    await operator.call(contract, "unregister_agents", {
+        service_id: 1,
+        agent_instances: [agentInstance]
+    });
+    result = await contract.view("get_registry_balance", {});
+    t.is(result, agentBonds[0]);
+});
+
+test("Replace the agent instance of the deployed service approved by the multisig", async t => {
+    const {root, contract, deployer, operator, agentInstance, agentInstance2} = t.context.accounts;
+
+    // Initialize the contract and approve the root as the factory of existing multisigs
+    await root.call(contract, "new", {
+        multisig_factory: deployer,
+        metadata: defaultContractMetadata
+    });
+    await root.call(contract, "add_multisig_factory", {multisig_factory: root, kind: "Multisig2"});
+
+    // Deploy the test multisig that returns preset members
+    const multisig = await root.createSubAccount("multisig", {initialBalance: NEAR.parse("10 N").toJSON()});
+    await multisig.deploy("target/wasm32-unknown-unknown/release/test_multisig_factory.wasm");
+    await multisig.call(multisig, "new", {});
+    await multisig.call(multisig, "set_members", {members: [{account_id: agentInstance.accountId}], num_confirmations: 1});
+
+    // Create, register and deploy the service
+    const attachedDeposit = "5 N";
+    await root.call(contract, "create", {
+        service_owner: deployer,
+        metadata: defaultServiceMetadata,
+        config_hash: configHash,
+        agent_ids: agentIds,
+        agent_num_instances: agentNumInstances,
+        agent_bonds: agentBonds,
+        threshold
+    }, {attachedDeposit});
+    await deployer.call(contract, "activate_registration", {
+        service_id: serviceId,
+    }, {attachedDeposit});
+    await operator.call(contract, "register_agents", {
+        service_id: serviceId,
+        agent_instances: [agentInstance],
+        agent_ids: agentIds
+    }, {attachedDeposit});
+    await deployer.call(contract, "deploy", {
+        service_id: serviceId,
+        name_multisig: multisig
+    }, {gas: "300 Tgas"});
+
+    // Only the agent instance registered in the service can be replaced
+    await t.throwsAsync(operator.call(contract, "replace_agent_instance", {
+        service_id: serviceId,
+        old_instance: agentInstance2,
+        new_instance: agentInstance
+    }, {attachedDeposit: "1 N", gas: "300 Tgas"}), {message: /E019: Wrong agent instance/});
+
+    // The new agent instance needs to consent as when registering
+    await root.call(contract, "set_instance_consent_required", {required: true});
+    await t.throwsAsync(operator.call(contract, "replace_agent_instance", {
+        service_id: serviceId,
+        old_instance: agentInstance,
+        new_instance: agentInstance2
+    }, {attachedDeposit: "1 N", gas: "300 Tgas"}), {message: /E033/});
+    await agentInstance2.call(contract, "accept_operator", {service_id: serviceId, operator}, {attachedDeposit: "0.1 N"});
+
+    // The replacement is not recorded before the multisig swaps its members, and the consent is kept
+    const outcome = await operator.callRaw(contract, "replace_agent_instance", {
+        service_id: serviceId,
+        old_instance: agentInstance,
+        new_instance: agentInstance2
+    }, {attachedDeposit: "1 N", gas: "300 Tgas"});
+    const event = parseEvents(outcome).find(e => e.event === "replace_instance_failed");
+    t.truthy(event);
+    let result = await contract.view("get_service_agent_instances", {service_id: serviceId});
+    t.deepEqual(result, [agentInstance.accountId]);
+    result = await contract.view("get_instance_consent", {agent_instance: agentInstance2});
+    t.deepEqual(result, {service_id: serviceId, operator_id: operator.accountId});
+
+    // The multisig swaps its members and the replacement is recorded, consuming the consent
+    await multisig.call(multisig, "set_members", {members: [{account_id: agentInstance2.accountId}], num_confirmations: 1});
+    await operator.call(contract, "replace_agent_instance", {
+        service_id: serviceId,
+        old_instance: agentInstance,
+        new_instance: agentInstance2
+    }, {attachedDeposit: "1 N", gas: "300 Tgas"});
+    result = await contract.view("get_service_agent_instances", {service_id: serviceId});
+    t.deepEqual(result, [agentInstance2.accountId]);
+    result = await contract.view("get_operator_service_agent_instances", {operator, service_id: serviceId});
+    t.deepEqual(result, [agentInstance2.accountId]);
+    result = await contract.view("get_instance_consent", {agent_instance: agentInstance2});
+    t.is(result, null);
+});
+
+test("Expire the service registration after its deadline", async t => {
+    const {root, contract, deployer, operator, agentInstance} = t.context.accounts;
+
+    // Initialize the contract
+    await root.call(contract, "new", {
+        multisig_factory: deployer,
+        metadata: defaultContractMetadata
+    });
+
+    // Create service with two agent instances
+    const attachedDeposit = "5 N";
+    await root.call(contract, "create", {
+        service_owner: deployer,
+        metadata: defaultServiceMetadata,
+        config_hash: configHash,
+        agent_ids: agentIds,
+        agent_num_instances: [2],
+        agent_bonds: agentBonds,
+        threshold: 2
+    }, {attachedDeposit});
+
+    // The registration deadline must be in the future
+    const block = await t.context.worker.provider.block({finality: "final"});
+    const now = Math.floor(Number(block.header.timestamp) / 1e9);
+    await t.throwsAsync(deployer.call(contract, "activate_registration", {
+        service_id: serviceId,
+        registration_deadline: now - 1
+    }, {attachedDeposit}), {message: /E036/});
+
+    // Activate the registration with the deadline and register one agent instance
+    const deadline = now + 5;
+    await deployer.call(contract, "activate_registration", {
+        service_id: serviceId,
+        registration_deadline: deadline
+    }, {attachedDeposit});
+    await operator.call(contract, "register_agents", {
+        service_id: serviceId,
+        agent_instances: [agentInstance],
+        agent_ids: agentIds
+    }, {attachedDeposit});
+    let result = await contract.view("get_registration_deadline", {service_id: serviceId});
+    t.is(result, deadline);
+
+    // The registration cannot be expired before the deadline
+    await t.throwsAsync(operator.call(contract, "expire_registration", {service_id: serviceId}), {message: /E037/});
+
+    // Anyone expires the registration after the deadline
+    await t.context.worker.provider.fastForward(100);
+    const ownerBalanceBefore = await deployer.balance();
+    const outcome = await operator.callRaw(contract, "expire_registration", {service_id: serviceId});
+    result = await contract.view("get_service_state", {service_id: serviceId});
+    t.is(result, 5);
+
+    // The service owner gets the security deposit back
+    const event = parseEvents(outcome).find(e => e.event === "terminate_service");
+    t.is(event.data.owner_id, deployer.accountId);
+    t.is(event.data.refund, agentBonds[0].toString());
+    const ownerBalanceAfter = await deployer.balance();
+    t.true(ownerBalanceAfter.total.sub(ownerBalanceBefore.total).gten(agentBonds[0]));
+
+    // The operator unbonds
+    await operator.call(contract, "unbond", {service_id: serviceId});
+    result = await contract.view("get_service_state", {service_id: serviceId});
+    t.is(result, 1);
+});
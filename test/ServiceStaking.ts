@@ -0,0 +1,262 @@
+import {Worker, NEAR, NearAccount} from "near-workspaces";
+import anyTest, {TestFn} from "ava";
+
+const configHash = Array(32).fill(5);
+
+const defaultContractMetadata = {
+    spec: "nft-1.0.0",
+    name: "Service Registry NFT",
+    symbol: "SR",
+    icon: null,
+    base_uri: "https://gateway.autonolas.tech/ipfs/"
+}
+
+const defaultServiceMetadata = {
+    title: "Service Name",
+    description: "Service Description",
+    media: "",
+    media_hash: "",
+    copies: 1,
+    issued_at: "",
+    expires_at: "",
+    starts_at: "",
+    updated_at: "",
+    extra: "",
+    reference: "",
+    reference_hash: "",
+}
+
+const defaultStakingParams = {
+    agent_ids: [1],
+    threshold: 1,
+    num_agent_instances: 1,
+    min_staking_deposit: "1000",
+    staking_token: null,
+    max_num_services: 2,
+    rewards_per_second: "1000000000000000",
+    min_staking_duration: 86400,
+    liveness_period: 3600,
+    liveness_ratio: "11111111111111",
+    reward_token: null
+}
+
+// Fast forwards blocks until the block time reaches the provided time in seconds
+async function fastForwardTo(worker: Worker, ts: number) {
+    for (;;) {
+        const block = await worker.provider.block({finality: "final"});
+        const now = Math.floor(Number(block.header.timestamp) / 1e9);
+        if (now >= ts) {
+            return;
+        }
+        await worker.provider.fastForward(ts - now);
+    }
+}
+
+// Creates, registers and deploys the service with the test multisig, then returns the multisig
+async function deployService(root: NearAccount, contract: NearAccount, deployer: NearAccount): Promise<NearAccount> {
+    const operator = await root.createSubAccount("operator", {initialBalance: NEAR.parse("20 N").toJSON()});
+    const agentInstance = await root.createSubAccount("agent_instance", {initialBalance: NEAR.parse("1 N").toJSON()});
+
+    // Approve the root as the factory of existing multisigs
+    await root.call(contract, "add_multisig_factory", {multisig_factory: root, kind: "Multisig2"});
+
+    // Deploy the test multisig that returns preset members and the request nonce
+    const multisig = await root.createSubAccount("multisig", {initialBalance: NEAR.parse("10 N").toJSON()});
+    await multisig.deploy("target/wasm32-unknown-unknown/release/test_multisig_factory.wasm");
+    await multisig.call(multisig, "new", {});
+    await multisig.call(multisig, "set_members", {members: [{account_id: agentInstance.accountId}], num_confirmations: 1});
+
+    const attachedDeposit = "5 N";
+    await deployer.call(contract, "create", {
+        service_owner: deployer,
+        metadata: defaultServiceMetadata,
+        config_hash: configHash,
+        agent_ids: [1],
+        agent_num_instances: [1],
+        agent_bonds: [1000],
+        threshold: 1
+    }, {attachedDeposit});
+    await deployer.call(contract, "activate_registration", {service_id: 1}, {attachedDeposit});
+    await operator.call(contract, "register_agents", {
+        service_id: 1,
+        agent_instances: [agentInstance],
+        agent_ids: [1]
+    }, {attachedDeposit});
+    await deployer.call(contract, "deploy", {service_id: 1, name_multisig: multisig}, {gas: "300 Tgas"});
+
+    return multisig;
+}
+
+const test = anyTest as TestFn<{
+    worker: Worker;
+    accounts: Record<string, NearAccount>;
+}>;
+
+test.beforeEach(async t => {
+    // Init the worker and start a Sandbox server
+    const worker = await Worker.init();
+
+    // Prepare sandbox for tests, create accounts, deploy contracts, etx.
+    const root = worker.rootAccount;
+    // Deploy the service registry contract
+    const contract = await root.devDeploy(
+        "target/wasm32-unknown-unknown/release/registries_near.wasm",
+        {initialBalance: NEAR.parse("20 N").toJSON()},
+    );
+    // Deploy the service staking contract
+    const staking = await root.devDeploy(
+        "target/wasm32-unknown-unknown/release/service_staking.wasm",
+        {initialBalance: NEAR.parse("20 N").toJSON()},
+    );
+
+    // Allocate accounts
+    const deployer = await root.createSubAccount("deployer", {initialBalance: NEAR.parse("100 N").toJSON()});
+
+    // Initialize contracts
+    await root.call(contract, "new", {
+        multisig_factory: deployer,
+        metadata: defaultContractMetadata
+    });
+    await root.call(staking, "new", {
+        service_registry: contract,
+        params: defaultStakingParams
+    });
+
+    // Save state for test runs, it is unique for each test
+    t.context.worker = worker;
+    t.context.accounts = {root, contract, staking, deployer};
+});
+
+test.afterEach.always(async t => {
+    await t.context.worker.tearDown().catch(error => {
+        console.log('Failed to tear down the worker:', error);
+    });
+});
+
+test("Deposit rewards", async t => {
+    const {staking, deployer} = t.context.accounts;
+
+    await deployer.call(staking, "deposit", {}, {attachedDeposit: "10 N"});
+
+    const result = await staking.view("get_available_rewards", {});
+    t.is(result, NEAR.parse("10 N").toString());
+});
+
+test("Try to stake a service that is not deployed", async t => {
+    const {contract, staking, deployer} = t.context.accounts;
+
+    // Create service
+    await deployer.call(contract, "create", {
+        service_owner: deployer,
+        metadata: defaultServiceMetadata,
+        config_hash: configHash,
+        agent_ids: [1],
+        agent_num_instances: [1],
+        agent_bonds: [1000],
+        threshold: 1
+    }, {attachedDeposit: "5 N"});
+
+    // Try to stake the service
+    await deployer.call(contract, "nft_transfer_call", {
+        receiver_id: staking,
+        token_id: "1",
+        msg: ""
+    }, {attachedDeposit: "1", gas: "300 Tgas"});
+
+    // The service NFT is returned to its owner
+    const token: any = await contract.view("nft_token", {token_id: "1"});
+    t.is(token.owner_id, deployer.accountId);
+    const result = await staking.view("get_service_ids", {});
+    t.deepEqual(result, []);
+});
+
+test("Stake the service, checkpoint, claim the reward and unstake", async t => {
+    const {root, contract, staking, deployer} = t.context.accounts;
+
+    const multisig = await deployService(root, contract, deployer);
+    await deployer.call(staking, "deposit", {}, {attachedDeposit: "10 N"});
+
+    // Stake the service
+    await deployer.call(contract, "nft_transfer_call", {
+        receiver_id: staking,
+        token_id: "1",
+        msg: ""
+    }, {attachedDeposit: "1", gas: "300 Tgas"});
+    let token: any = await contract.view("nft_token", {token_id: "1"});
+    t.is(token.owner_id, staking.accountId);
+    const serviceIds: number[] = await staking.view("get_service_ids", {});
+    t.deepEqual(serviceIds, [1]);
+
+    // The checkpoint is not due before the liveness period
+    await t.throwsAsync(deployer.call(staking, "checkpoint", {}, {gas: "300 Tgas"}), {message: /E004/});
+
+    // The multisig makes requests during the liveness period
+    await multisig.call(multisig, "set_request_nonce", {request_nonce: 5});
+    const checkpointTs: number = await staking.view("get_next_checkpoint_ts", {});
+    await fastForwardTo(t.context.worker, checkpointTs);
+
+    // The checkpoint rewards the live service
+    await deployer.call(staking, "checkpoint", {}, {gas: "300 Tgas"});
+    const epoch: number = await staking.view("get_epoch_counter", {});
+    t.is(epoch, 1);
+    let info: any = await staking.view("get_service_info", {service_id: 1});
+    t.is(info.nonce, 5);
+    const reward = BigInt(info.reward);
+    t.true(reward > BigInt(0));
+    const availableRewards: string = await staking.view("get_available_rewards", {});
+    t.is(BigInt(availableRewards) + reward, BigInt(NEAR.parse("10 N").toString()));
+
+    // Only the service owner claims the reward, which is sent to the multisig
+    await t.throwsAsync(multisig.call(staking, "claim", {service_id: 1}, {gas: "300 Tgas"}), {message: /E001/});
+    const multisigBalanceBefore = await multisig.balance();
+    await deployer.call(staking, "claim", {service_id: 1}, {gas: "300 Tgas"});
+    const multisigBalanceAfter = await multisig.balance();
+    t.is(BigInt(multisigBalanceAfter.total.sub(multisigBalanceBefore.total).toString()), reward);
+    info = await staking.view("get_service_info", {service_id: 1});
+    t.is(info.reward, "0");
+    await t.throwsAsync(deployer.call(staking, "claim", {service_id: 1}, {gas: "300 Tgas"}), {message: /E005/});
+
+    // The service is unstaked after the minimum staking duration
+    await t.throwsAsync(deployer.call(staking, "unstake", {service_id: 1}, {gas: "300 Tgas"}), {message: /E006/});
+    await fastForwardTo(t.context.worker, info.ts_start + defaultStakingParams.min_staking_duration);
+    await deployer.call(staking, "unstake", {service_id: 1}, {gas: "300 Tgas"});
+
+    // The service NFT is returned to its owner
+    token = await contract.view("nft_token", {token_id: "1"});
+    t.is(token.owner_id, deployer.accountId);
+    info = await staking.view("get_service_info", {service_id: 1});
+    t.is(info, null);
+});
+
+test("Scale large rewards down to available rewards without an overflow", async t => {
+    const {root, contract, deployer} = t.context.accounts;
+
+    // Rewards per second such that the reward multiplied by available rewards exceeds 128 bits
+    const staking = await root.devDeploy(
+        "target/wasm32-unknown-unknown/release/service_staking.wasm",
+        {initialBalance: NEAR.parse("20 N").toJSON()},
+    );
+    await root.call(staking, "new", {
+        service_registry: contract,
+        params: {...defaultStakingParams, rewards_per_second: "1000000000000000000000000000000"}
+    });
+
+    const multisig = await deployService(root, contract, deployer);
+    await deployer.call(staking, "deposit", {}, {attachedDeposit: "1 N"});
+    await deployer.call(contract, "nft_transfer_call", {
+        receiver_id: staking,
+        token_id: "1",
+        msg: ""
+    }, {attachedDeposit: "1", gas: "300 Tgas"});
+
+    await multisig.call(multisig, "set_request_nonce", {request_nonce: 5});
+    const checkpointTs: number = await staking.view("get_next_checkpoint_ts", {});
+    await fastForwardTo(t.context.worker, checkpointTs);
+    await deployer.call(staking, "checkpoint", {}, {gas: "300 Tgas"});
+
+    // The only eligible service gets all the available rewards
+    const info: any = await staking.view("get_service_info", {service_id: 1});
+    t.is(info.reward, NEAR.parse("1 N").toString());
+    const availableRewards: string = await staking.view("get_available_rewards", {});
+    t.is(availableRewards, "0");
+});
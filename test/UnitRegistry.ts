@@ -0,0 +1,197 @@
+import {Worker, NEAR, NearAccount} from "near-workspaces";
+import anyTest, {TestFn} from "ava";
+
+const componentHash = Array(32).fill(1);
+const agentHash = Array(32).fill(2);
+const agentHash2 = Array(32).fill(3);
+const configHash = Array(32).fill(5);
+
+const defaultContractMetadata = {
+    spec: "nft-1.0.0",
+    name: "Unit Registry NFT",
+    symbol: "UR",
+    icon: null,
+    base_uri: "https://gateway.autonolas.tech/ipfs/"
+}
+
+const defaultUnitMetadata = {
+    title: "Unit Name",
+    description: "Unit Description",
+    media: "",
+    media_hash: "",
+    copies: 1,
+    issued_at: "",
+    expires_at: "",
+    starts_at: "",
+    updated_at: "",
+    extra: "",
+    reference: "",
+    reference_hash: "",
+}
+
+const test = anyTest as TestFn<{
+    worker: Worker;
+    accounts: Record<string, NearAccount>;
+}>;
+
+test.beforeEach(async t => {
+    // Init the worker and start a Sandbox server
+    const worker = await Worker.init();
+
+    // Prepare sandbox for tests, create accounts, deploy contracts, etx.
+    const root = worker.rootAccount;
+    // Deploy component and agent registry contracts
+    const componentRegistry = await root.devDeploy(
+        "target/wasm32-unknown-unknown/release/unit_registry.wasm",
+        {initialBalance: NEAR.parse("20 N").toJSON()},
+    );
+    const agentRegistry = await root.devDeploy(
+        "target/wasm32-unknown-unknown/release/unit_registry.wasm",
+        {initialBalance: NEAR.parse("20 N").toJSON()},
+    );
+    // Deploy the service registry contract
+    const contract = await root.devDeploy(
+        "target/wasm32-unknown-unknown/release/registries_near.wasm",
+        {initialBalance: NEAR.parse("20 N").toJSON()},
+    );
+
+    // Allocate accounts
+    const deployer = await root.createSubAccount("deployer", {initialBalance: NEAR.parse("100 N").toJSON()});
+
+    // Initialize registries
+    await root.call(componentRegistry, "new", {
+        unit_type: "Component",
+        metadata: defaultContractMetadata
+    });
+    await root.call(agentRegistry, "new", {
+        unit_type: "Agent",
+        component_registry: componentRegistry,
+        metadata: defaultContractMetadata
+    });
+
+    // Save state for test runs, it is unique for each test
+    t.context.worker = worker;
+    t.context.accounts = {root, componentRegistry, agentRegistry, contract, deployer};
+});
+
+test.afterEach.always(async t => {
+    await t.context.worker.tearDown().catch(error => {
+        console.log('Failed to tear down the worker:', error);
+    });
+});
+
+test("Create component and agent and update the agent hash", async t => {
+    const {componentRegistry, agentRegistry, deployer} = t.context.accounts;
+
+    // Create a component
+    const attachedDeposit = "1 N";
+    await deployer.call(componentRegistry, "create", {
+        unit_owner: deployer,
+        metadata: defaultUnitMetadata,
+        unit_hash: componentHash,
+        dependencies: []
+    }, {attachedDeposit});
+
+    // Create an agent depending on the component
+    await deployer.call(agentRegistry, "create", {
+        unit_owner: deployer,
+        metadata: defaultUnitMetadata,
+        unit_hash: agentHash,
+        dependencies: [1]
+    }, {attachedDeposit, gas: "300 Tgas"});
+
+    // Check the agent
+    let result: any = await agentRegistry.view("get_unit", {unit_id: 1});
+    t.is(result.unit_type, "Agent");
+    t.is(result.owner_id, deployer.accountId);
+    t.deepEqual(result.unit_hash, agentHash);
+    t.deepEqual(result.dependencies, [1]);
+
+    // Update the agent hash
+    await deployer.call(agentRegistry, "update_hash", {
+        unit_id: 1,
+        unit_hash: agentHash2
+    }, {attachedDeposit});
+
+    result = await agentRegistry.view("get_unit", {unit_id: 1});
+    t.deepEqual(result.unit_hash, agentHash2);
+    result = await agentRegistry.view("get_unit_hashes", {unit_id: 1});
+    t.deepEqual(result, [agentHash, agentHash2]);
+});
+
+test("Try to create an agent with a non-existent component dependency", async t => {
+    const {agentRegistry, deployer} = t.context.accounts;
+
+    // Create an agent depending on a non-existent component
+    await deployer.call(agentRegistry, "create", {
+        unit_owner: deployer,
+        metadata: defaultUnitMetadata,
+        unit_hash: agentHash,
+        dependencies: [1]
+    }, {attachedDeposit: "1 N", gas: "300 Tgas"});
+
+    // Check that the agent was not created
+    const result = await agentRegistry.view("total_supply", {});
+    t.is(result, "0");
+});
+
+test("Create services only with agent ids existing in the agent registry", async t => {
+    const {root, componentRegistry, agentRegistry, contract, deployer} = t.context.accounts;
+
+    // Initialize the service registry with the agent registry
+    await root.call(contract, "new", {
+        multisig_factory: deployer,
+        metadata: defaultContractMetadata,
+        agent_registry: agentRegistry
+    });
+
+    // Create a component and an agent
+    const attachedDeposit = "5 N";
+    await deployer.call(componentRegistry, "create", {
+        unit_owner: deployer,
+        metadata: defaultUnitMetadata,
+        unit_hash: componentHash,
+        dependencies: []
+    }, {attachedDeposit});
+    await deployer.call(agentRegistry, "create", {
+        unit_owner: deployer,
+        metadata: defaultUnitMetadata,
+        unit_hash: agentHash,
+        dependencies: [1]
+    }, {attachedDeposit, gas: "300 Tgas"});
+
+    // Try to create a service with a non-existent agent
+    const balanceBefore = await deployer.balance();
+    let result = await deployer.call(contract, "create", {
+        service_owner: deployer,
+        metadata: defaultUnitMetadata,
+        config_hash: configHash,
+        agent_ids: [2],
+        agent_num_instances: [1],
+        agent_bonds: [1000],
+        threshold: 1
+    }, {attachedDeposit, gas: "300 Tgas"});
+    t.is(result, false);
+
+    // Check that the service was not created and the deposit is returned
+    result = await contract.view("total_supply", {});
+    t.is(result, "0");
+    const balanceAfter = await deployer.balance();
+    t.true(balanceBefore.available.sub(balanceAfter.available).lt(NEAR.parse("0.1 N")));
+
+    // Create a service with the existing agent
+    result = await deployer.call(contract, "create", {
+        service_owner: deployer,
+        metadata: defaultUnitMetadata,
+        config_hash: configHash,
+        agent_ids: [1],
+        agent_num_instances: [1],
+        agent_bonds: [1000],
+        threshold: 1
+    }, {attachedDeposit, gas: "300 Tgas"});
+    t.is(result, true);
+
+    // Check that the service is in the PreRegistration state
+    result = await contract.view("get_service_state", {service_id: 1});
+    t.is(result, 1);
+});